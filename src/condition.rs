@@ -0,0 +1,446 @@
+use crate::rules_handler::{MemberInfo, RoleErrors, Rule, ScheduleWindow, StatusKind};
+use std::fmt::{self, Display};
+use twilight_model::gateway::presence::Activity;
+
+/// A rule's condition, e.g.
+/// `activity:"tekken 8" AND voice AND NOT status:dnd` or `role:<@&123> OR joined:30d`.
+/// `NOT` binds tighter than `AND`, which binds tighter than `OR`
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Condition {
+    And(Vec<Condition>),
+    Or(Vec<Condition>),
+    Not(Box<Condition>),
+    Predicate(Predicate),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Predicate {
+    /// An activity the rule applies to matches the keyword, with the rule's match mode
+    Activity(String),
+    Status(StatusKind),
+    Role(u64),
+    /// Connected to one of the guild's voice channels
+    Voice,
+    /// Member of the guild for at least this many days
+    JoinedDays(u64),
+    /// Within the weekly window, in the guild's time zone
+    Time(ScheduleWindow),
+}
+
+/// What a condition is evaluated against
+pub struct ConditionContext<'a> {
+    pub rule: &'a Rule,
+    pub activities: &'a [Activity],
+    pub status: StatusKind,
+    pub member: &'a MemberInfo,
+    pub minute_of_week: u32,
+    /// Milliseconds since the unix epoch
+    pub now: u64,
+    /// The guild's setting, activity names are only lowercased when it's set
+    pub raw_activity_names: bool,
+}
+
+const DAY_MILLIS: u64 = 24 * 60 * 60 * 1000;
+
+/// How deep parentheses and `NOT`s may nest, the parser recurses for each level
+const MAX_NESTING: usize = 32;
+
+impl Condition {
+    pub fn parse(s: &str) -> Result<Self, RoleErrors> {
+        let tokens = tokenize(s)?;
+        let mut parser = Parser {
+            tokens: &tokens,
+            position: 0,
+            depth: 0,
+        };
+        let condition = parser.parse_or()?;
+        match parser.tokens.get(parser.position) {
+            None => Ok(condition),
+            Some(token) => Err(invalid(format!("unexpected {}", token))),
+        }
+    }
+
+    pub fn holds(&self, context: &ConditionContext) -> bool {
+        match self {
+            Condition::And(conditions) => conditions.iter().all(|c| c.holds(context)),
+            Condition::Or(conditions) => conditions.iter().any(|c| c.holds(context)),
+            Condition::Not(condition) => !condition.holds(context),
+            Condition::Predicate(predicate) => predicate.holds(context),
+        }
+    }
+
+    /// The activities an `activity:` predicate matched in the parts of the condition that hold,
+    /// negated predicates count none
+    pub fn matched_activities<'a>(&self, context: &ConditionContext<'a>) -> Vec<&'a Activity> {
+        match self {
+            Condition::And(conditions) | Condition::Or(conditions) => conditions
+                .iter()
+                .filter(|condition| condition.holds(context))
+                .flat_map(|condition| condition.matched_activities(context))
+                .collect(),
+            Condition::Not(_) => Vec::new(),
+            Condition::Predicate(predicate) => predicate.matched_activities(context),
+        }
+    }
+
+    /// Whether each `time` window in the condition is open, in order
+    pub fn open_windows(&self, minute_of_week: u32) -> Vec<bool> {
+        match self {
+            Condition::And(conditions) | Condition::Or(conditions) => conditions
+                .iter()
+                .flat_map(|condition| condition.open_windows(minute_of_week))
+                .collect(),
+            Condition::Not(condition) => condition.open_windows(minute_of_week),
+            Condition::Predicate(Predicate::Time(window)) => vec![window.contains(minute_of_week)],
+            Condition::Predicate(_) => Vec::new(),
+        }
+    }
+
+    /// Whether the `voice` predicate is anywhere in the condition
+    pub fn uses_voice(&self) -> bool {
+        match self {
+            Condition::And(conditions) | Condition::Or(conditions) => {
+                conditions.iter().any(Condition::uses_voice)
+            }
+            Condition::Not(condition) => condition.uses_voice(),
+            Condition::Predicate(predicate) => *predicate == Predicate::Voice,
+        }
+    }
+
+    fn fmt_operand(&self, f: &mut fmt::Formatter<'_>, parent_binds_tighter: bool) -> fmt::Result {
+        match parent_binds_tighter {
+            true => write!(f, "({})", self),
+            false => write!(f, "{}", self),
+        }
+    }
+}
+
+impl Predicate {
+    fn holds(&self, context: &ConditionContext) -> bool {
+        match self {
+            Predicate::Activity(_) => !self.matched_activities(context).is_empty(),
+            Predicate::Status(status) => context.status == *status,
+            Predicate::Role(role_id) => context.member.roles.contains(role_id),
+            Predicate::Voice => context.member.in_voice,
+            Predicate::JoinedDays(days) => context.member.joined_at.is_some_and(|joined_at| {
+                context.now.saturating_sub(joined_at) >= days.saturating_mul(DAY_MILLIS)
+            }),
+            Predicate::Time(window) => window.contains(context.minute_of_week),
+        }
+    }
+
+    fn matched_activities<'a>(&self, context: &ConditionContext<'a>) -> Vec<&'a Activity> {
+        match self {
+            Predicate::Activity(keyword) => context
+                .activities
+                .iter()
+                .filter(|activity| {
+                    context
+                        .rule
+                        .accepts_activity(activity, context.raw_activity_names)
+                        && context.rule.matches_keyword(
+                            &activity.name,
+                            keyword,
+                            context.raw_activity_names,
+                        )
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn parse(word: &str) -> Result<Self, RoleErrors> {
+        if word.eq_ignore_ascii_case("voice") {
+            return Ok(Predicate::Voice);
+        }
+
+        let (key, value) = word
+            .split_once(':')
+            .ok_or(invalid(format!("expected `name:value`, got {}", word)))?;
+        let value = value.trim();
+        match key.to_lowercase().as_str() {
+            "activity" if !value.is_empty() => Ok(Predicate::Activity(value.to_string())),
+            "status" => StatusKind::from_str(&value.to_lowercase())
+                .map(Predicate::Status)
+                .ok_or(invalid(format!("unknown status {}", value))),
+            "role" => value
+                .trim_start_matches("<@&")
+                .trim_end_matches('>')
+                .parse()
+                .map(Predicate::Role)
+                .map_err(|_| invalid(format!("invalid role {}", value))),
+            "joined" => value
+                .trim_end_matches('d')
+                .parse()
+                .map(Predicate::JoinedDays)
+                .map_err(|_| invalid(format!("invalid days {}", value))),
+            "time" => ScheduleWindow::from_str(value)
+                .map(Predicate::Time)
+                .ok_or(invalid(format!("invalid time window {}", value))),
+            _ => Err(invalid(format!("unknown predicate {}", word))),
+        }
+    }
+}
+
+impl Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Condition::Or(conditions) => {
+                for (i, condition) in conditions.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " OR ")?;
+                    }
+                    condition.fmt_operand(f, false)?;
+                }
+                Ok(())
+            }
+            Condition::And(conditions) => {
+                for (i, condition) in conditions.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " AND ")?;
+                    }
+                    condition.fmt_operand(f, matches!(condition, Condition::Or(_)))?;
+                }
+                Ok(())
+            }
+            Condition::Not(condition) => {
+                write!(f, "NOT ")?;
+                condition.fmt_operand(
+                    f,
+                    matches!(**condition, Condition::And(_) | Condition::Or(_)),
+                )
+            }
+            Condition::Predicate(predicate) => write!(f, "{}", predicate),
+        }
+    }
+}
+
+impl Display for Predicate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Predicate::Activity(keyword) => write!(f, "activity:\"{}\"", keyword),
+            Predicate::Status(status) => write!(f, "status:{}", status.to_str()),
+            Predicate::Role(role_id) => write!(f, "role:<@&{}>", role_id),
+            Predicate::Voice => write!(f, "voice"),
+            Predicate::JoinedDays(days) => write!(f, "joined:{}d", days),
+            Predicate::Time(window) => write!(f, "time:\"{}\"", window),
+        }
+    }
+}
+
+fn invalid(reason: String) -> RoleErrors {
+    RoleErrors::InvalidCondition(reason)
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    And,
+    Or,
+    Not,
+    /// A predicate, quotes already removed from its value
+    Word(String),
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Open => write!(f, "`(`"),
+            Token::Close => write!(f, "`)`"),
+            Token::And => write!(f, "`AND`"),
+            Token::Or => write!(f, "`OR`"),
+            Token::Not => write!(f, "`NOT`"),
+            Token::Word(word) => write!(f, "`{}`", word),
+        }
+    }
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, RoleErrors> {
+    let mut tokens = Vec::new();
+    let mut chars = s.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => continue,
+            '(' => tokens.push(Token::Open),
+            ')' => tokens.push(Token::Close),
+            c => {
+                let mut word = String::new();
+                let mut next = Some(c);
+                while let Some(c) = next {
+                    match c {
+                        '"' => loop {
+                            match chars.next() {
+                                Some('"') => break,
+                                Some(c) => word.push(c),
+                                None => return Err(invalid("unclosed quote".to_string())),
+                            }
+                        },
+                        c if c.is_whitespace() || c == '(' || c == ')' => break,
+                        c => word.push(c),
+                    }
+                    next = chars.next_if(|c| !c.is_whitespace() && *c != '(' && *c != ')');
+                }
+                tokens.push(match word.to_uppercase().as_str() {
+                    "AND" => Token::And,
+                    "OR" => Token::Or,
+                    "NOT" => Token::Not,
+                    _ => Token::Word(word),
+                });
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: &'a [Token],
+    position: usize,
+    /// Parentheses and `NOT`s entered so far
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn next_is(&mut self, token: Token) -> bool {
+        let is_next = self.tokens.get(self.position) == Some(&token);
+        if is_next {
+            self.position += 1;
+        }
+        is_next
+    }
+
+    /// Parses one nesting level deeper, refusing conditions nested deeper than `MAX_NESTING`
+    /// rather than overflowing the stack
+    fn nested(
+        &mut self,
+        parse: impl FnOnce(&mut Self) -> Result<Condition, RoleErrors>,
+    ) -> Result<Condition, RoleErrors> {
+        if self.depth == MAX_NESTING {
+            return Err(invalid(format!(
+                "nested deeper than {} levels",
+                MAX_NESTING
+            )));
+        }
+        self.depth += 1;
+        let condition = parse(self);
+        self.depth -= 1;
+        condition
+    }
+
+    fn parse_or(&mut self) -> Result<Condition, RoleErrors> {
+        let mut conditions = vec![self.parse_and()?];
+        while self.next_is(Token::Or) {
+            conditions.push(self.parse_and()?);
+        }
+        Ok(match conditions.len() {
+            1 => conditions.remove(0),
+            _ => Condition::Or(conditions),
+        })
+    }
+
+    fn parse_and(&mut self) -> Result<Condition, RoleErrors> {
+        let mut conditions = vec![self.parse_not()?];
+        while self.next_is(Token::And) {
+            conditions.push(self.parse_not()?);
+        }
+        Ok(match conditions.len() {
+            1 => conditions.remove(0),
+            _ => Condition::And(conditions),
+        })
+    }
+
+    fn parse_not(&mut self) -> Result<Condition, RoleErrors> {
+        if self.next_is(Token::Not) {
+            return self.nested(|parser| Ok(Condition::Not(Box::new(parser.parse_not()?))));
+        }
+
+        let token = self.tokens.get(self.position);
+        self.position += 1;
+        match token {
+            Some(Token::Open) => {
+                let condition = self.nested(Self::parse_or)?;
+                match self.next_is(Token::Close) {
+                    true => Ok(condition),
+                    false => Err(invalid("missing `)`".to_string())),
+                }
+            }
+            Some(Token::Word(word)) => Predicate::parse(word).map(Condition::Predicate),
+            Some(token) => Err(invalid(format!("unexpected {}", token))),
+            None => Err(invalid("unexpected end".to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rules_handler::RoleType;
+
+    #[test]
+    fn test_parse() {
+        let condition = Condition::parse(
+            "activity:\"Tekken 8\" and voice AND not (status:dnd OR status:idle) or role:<@&10>",
+        )
+        .unwrap();
+        assert_eq!(
+            condition.to_string(),
+            "activity:\"Tekken 8\" AND voice AND NOT (status:dnd OR status:idle) OR role:<@&10>"
+        );
+        assert_eq!(Condition::parse(&condition.to_string()).unwrap(), condition);
+        assert!(condition.uses_voice());
+        assert!(
+            !Condition::parse("role:10 OR joined:30d")
+                .unwrap()
+                .uses_voice()
+        );
+        assert!(Condition::parse("activity:tekken AND").is_err());
+        assert!(Condition::parse("(voice").is_err());
+        assert!(Condition::parse("status:busy").is_err());
+        assert!(Condition::parse("joined:soon").is_err());
+        assert!(Condition::parse("time:\"fri 25:00-26:00\"").is_err());
+    }
+
+    #[test]
+    fn test_nesting_limit() {
+        let parenthesized =
+            |depth: usize| format!("{}voice{}", "(".repeat(depth), ")".repeat(depth));
+        assert!(Condition::parse(&parenthesized(MAX_NESTING)).is_ok());
+        assert!(Condition::parse(&parenthesized(MAX_NESTING + 1)).is_err());
+        assert!(Condition::parse(&parenthesized(100_000)).is_err());
+        assert!(Condition::parse(&format!("{}voice", "NOT ".repeat(100_000))).is_err());
+    }
+
+    #[test]
+    fn test_joined_days() {
+        let rule = Rule::new(
+            0,
+            "guild_name".to_string(),
+            1,
+            "role".to_string(),
+            RoleType::NamedActivity,
+        );
+        let day = 24 * 60 * 60 * 1000;
+        let member = MemberInfo {
+            joined_at: Some(day),
+            ..Default::default()
+        };
+        let holds = |condition: &str| {
+            Condition::parse(condition)
+                .unwrap()
+                .holds(&ConditionContext {
+                    rule: &rule,
+                    activities: &[],
+                    status: StatusKind::Online,
+                    member: &member,
+                    minute_of_week: 0,
+                    now: 31 * day,
+                    raw_activity_names: false,
+                })
+        };
+        assert!(holds("joined:30d"));
+        assert!(!holds("joined:31d"));
+        // Saturates rather than overflowing
+        assert!(!holds("joined:999999999999999d"));
+    }
+}
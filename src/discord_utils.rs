@@ -1,10 +1,10 @@
 use crate::{
-    events::{handle_presence_update, user_activities_from_presence},
-    rules_handler::GuildRules,
+    events::{MemberRoleHistory, handle_presence_update},
+    rules_handler::{GuildRules, UserPresence},
 };
 use anyhow::Result;
 use std::{
-    collections::{BTreeMap, BTreeSet, HashMap},
+    collections::{BTreeMap, HashMap},
     sync::Arc,
 };
 use tokio::{
@@ -12,22 +12,23 @@ use tokio::{
     task::JoinHandle,
 };
 use twilight_cache_inmemory::InMemoryCache;
-use twilight_http::Client;
+use twilight_http::{Client, error::ErrorType};
 use twilight_model::{
     application::interaction::Interaction,
+    channel::message::MessageFlags,
+    guild::Member,
     http::interaction::{InteractionResponse, InteractionResponseData, InteractionResponseType},
     id::{
         Id,
-        marker::{GuildMarker, UserMarker},
+        marker::{GuildMarker, RoleMarker, UserMarker},
     },
 };
+use twilight_util::builder::InteractionResponseDataBuilder;
 
-pub async fn get_all_guild_members(
-    client: &Client,
-    guild_id: Id<GuildMarker>,
-) -> Result<Vec<Id<UserMarker>>> {
+/// Every member of the guild, fetched a page at a time
+async fn fetch_guild_members(client: &Client, guild_id: Id<GuildMarker>) -> Result<Vec<Member>> {
     let mut after: Option<Id<UserMarker>> = None;
-    let mut user_ids = Vec::new();
+    let mut guild_members = Vec::new();
 
     loop {
         let members_result = client
@@ -42,12 +43,19 @@ pub async fn get_all_guild_members(
             break;
         }
 
-        user_ids.extend(members.iter().map(|m| m.user.id));
-
         after = Some(members.last().unwrap().user.id);
+        guild_members.extend(members);
     }
 
-    Ok(user_ids)
+    Ok(guild_members)
+}
+
+pub async fn get_all_guild_members(
+    client: &Client,
+    guild_id: Id<GuildMarker>,
+) -> Result<Vec<Id<UserMarker>>> {
+    let members = fetch_guild_members(client, guild_id).await?;
+    Ok(members.iter().map(|m| m.user.id).collect())
 }
 
 pub async fn purge_guild_roles(
@@ -55,30 +63,117 @@ pub async fn purge_guild_roles(
     rules: Arc<RwLock<BTreeMap<u64, GuildRules>>>,
     cache: Arc<InMemoryCache>,
     presence_update_tasks: Arc<Mutex<HashMap<(Id<GuildMarker>, Id<UserMarker>), JoinHandle<()>>>>,
+    member_histories: Arc<Mutex<HashMap<(Id<GuildMarker>, Id<UserMarker>), MemberRoleHistory>>>,
     guild_id: Id<GuildMarker>,
 ) -> Result<()> {
     let guild_members = get_all_guild_members(&http_client, guild_id).await?;
 
+    // Opted out and ineligible members go through the same path and lose their managed roles
     for user_id in guild_members {
-        let user_activities = match cache.presence(guild_id, user_id) {
-            Some(presence) => user_activities_from_presence(presence.activities().iter()),
-            None => BTreeSet::new(),
-        };
-
-        let future = handle_presence_update(
+        let future = refresh_member_roles(
             http_client.clone(),
             rules.clone(),
             cache.clone(),
             presence_update_tasks.clone(),
+            member_histories.clone(),
             guild_id,
             user_id,
-            user_activities,
         );
         tokio::spawn(future);
     }
     Ok(())
 }
 
+/// Re-evaluates a member's roles from their cached presence
+pub async fn refresh_member_roles(
+    http_client: Arc<Client>,
+    rules: Arc<RwLock<BTreeMap<u64, GuildRules>>>,
+    cache: Arc<InMemoryCache>,
+    presence_update_tasks: Arc<Mutex<HashMap<(Id<GuildMarker>, Id<UserMarker>), JoinHandle<()>>>>,
+    member_histories: Arc<Mutex<HashMap<(Id<GuildMarker>, Id<UserMarker>), MemberRoleHistory>>>,
+    guild_id: Id<GuildMarker>,
+    user_id: Id<UserMarker>,
+) {
+    let user_presence = match cache.presence(guild_id, user_id) {
+        Some(presence) => UserPresence::new(
+            presence.activities().iter(),
+            presence.status(),
+            presence.client_status(),
+        ),
+        None => UserPresence::offline(),
+    };
+
+    handle_presence_update(
+        http_client,
+        rules,
+        cache,
+        presence_update_tasks,
+        member_histories,
+        guild_id,
+        user_id,
+        user_presence,
+    )
+    .await;
+}
+
+/// Takes the role away from every member holding it, the cache can miss members who haven't
+/// been seen since the bot started. Every member is tried, the ones it couldn't be taken from are
+/// returned, members who left in the meantime don't have it anymore
+pub async fn remove_role_from_members(
+    http_client: &Client,
+    guild_id: Id<GuildMarker>,
+    role_id: Id<RoleMarker>,
+) -> Result<Vec<Id<UserMarker>>> {
+    let members = fetch_guild_members(http_client, guild_id).await?;
+
+    let mut failed = Vec::new();
+    for member in members
+        .iter()
+        .filter(|member| member.roles.contains(&role_id))
+    {
+        let user_id = member.user.id;
+        tracing::warn!("Removing Role {role_id:?} to {user_id:?} in {guild_id:?}");
+        match http_client
+            .remove_guild_member_role(guild_id, user_id, role_id)
+            .await
+        {
+            Ok(_) => {}
+            Err(e) if matches!(e.kind(), ErrorType::Response { status, .. } if status.get() == 404) =>
+                {}
+            Err(e) => {
+                tracing::error!(?e, ?user_id, "Couldn't remove the role");
+                failed.push(user_id);
+            }
+        }
+    }
+    Ok(failed)
+}
+
+/// Answers right away with a message only the member who interacted can see
+pub async fn interaction_ephemeral_reply(
+    client: &Client,
+    interaction: &Interaction,
+    content: &str,
+) -> Result<()> {
+    client
+        .interaction(interaction.application_id)
+        .create_response(
+            interaction.id,
+            &interaction.token,
+            &InteractionResponse {
+                kind: InteractionResponseType::ChannelMessageWithSource,
+                data: Some(
+                    InteractionResponseDataBuilder::new()
+                        .content(content)
+                        .flags(MessageFlags::EPHEMERAL)
+                        .build(),
+                ),
+            },
+        )
+        .await?;
+    Ok(())
+}
+
 pub async fn interaction_ack(client: &Client, interaction: &Interaction) -> Result<()> {
     client
         .interaction(interaction.application_id)
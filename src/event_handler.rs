@@ -1,9 +1,18 @@
 use crate::{
     config_handler::GithubConfig,
-    discord_utils::{interaction_ack, interaction_end, interaction_response, purge_guild_roles},
-    events::{easter, handle_presence_update, user_activities_from_presence},
-    interactions::command::{ManageCommand, StorageCommand},
-    rules_handler::{GuildRules, load_db, update_roles_names},
+    discord_utils::{
+        interaction_ack, interaction_end, interaction_ephemeral_reply, interaction_response,
+        purge_guild_roles, refresh_member_roles,
+    },
+    events::{MemberRoleHistory, easter, handle_presence_update},
+    interactions::{
+        command::{ActivityRolesCommand, ManageCommand, StorageCommand},
+        consent::consent_choice,
+    },
+    rules_handler::{
+        GuildRules, UserPresence, load_db, save_current_db_to_file, set_member_consent,
+        update_roles_names,
+    },
 };
 use anyhow::{Result, bail};
 use std::{
@@ -42,6 +51,7 @@ pub struct Bot {
     pub cache: Arc<InMemoryCache>,
     pub presence_update_tasks:
         Arc<Mutex<HashMap<(Id<GuildMarker>, Id<UserMarker>), JoinHandle<()>>>>,
+    pub member_histories: Arc<Mutex<HashMap<(Id<GuildMarker>, Id<UserMarker>), MemberRoleHistory>>>,
     pub github_config: Option<GithubConfig>,
 }
 
@@ -53,6 +63,7 @@ impl Bot {
                 .build(),
         );
         let presence_update_tasks = Arc::new(Mutex::new(HashMap::new()));
+        let member_histories = Arc::new(Mutex::new(HashMap::new()));
         let rules = Arc::new(RwLock::new(load_db(github_config.as_ref()).await));
 
         Self {
@@ -60,6 +71,7 @@ impl Bot {
             rules,
             cache,
             presence_update_tasks,
+            member_histories,
             github_config,
         }
     }
@@ -70,20 +82,46 @@ impl Bot {
             Event::PresenceUpdate(presence_update) => {
                 let guild_id = presence_update.guild_id;
                 let user_id = presence_update.user.id();
-                let user_activities =
-                    user_activities_from_presence(presence_update.activities.iter());
+                let user_presence = UserPresence::new(
+                    presence_update.activities.iter(),
+                    presence_update.status,
+                    &presence_update.client_status,
+                );
 
                 let future = handle_presence_update(
                     self.http_client.clone(),
                     self.rules.clone(),
                     self.cache.clone(),
                     self.presence_update_tasks.clone(),
+                    self.member_histories.clone(),
                     guild_id,
                     user_id,
-                    user_activities,
+                    user_presence,
                 );
                 tokio::spawn(future);
             }
+            // Voice conditions are re-evaluated when the member joins or leaves a channel, the
+            // runner drops the updates that didn't change it
+            Event::VoiceStateUpdate(voice_state_update) => {
+                if let Some(guild_id) = voice_state_update.guild_id
+                    && self
+                        .rules
+                        .read()
+                        .await
+                        .get(&guild_id.get())
+                        .is_some_and(GuildRules::uses_voice)
+                {
+                    tokio::spawn(refresh_member_roles(
+                        self.http_client.clone(),
+                        self.rules.clone(),
+                        self.cache.clone(),
+                        self.presence_update_tasks.clone(),
+                        self.member_histories.clone(),
+                        guild_id,
+                        voice_state_update.user_id,
+                    ));
+                }
+            }
             Event::GuildCreate(guild_create) => match *guild_create {
                 GuildCreate::Available(guild_data) => {
                     let guild_id = guild_data.id;
@@ -92,6 +130,7 @@ impl Bot {
                         self.rules.clone(),
                         self.cache.clone(),
                         self.presence_update_tasks.clone(),
+                        self.member_histories.clone(),
                         guild_id,
                     ));
                     tokio::spawn(easter(self.http_client.clone(), guild_id));
@@ -108,6 +147,12 @@ impl Bot {
                 let mut interaction = interaction.0;
                 let data = match mem::take(&mut interaction.data) {
                     Some(InteractionData::ApplicationCommand(data)) => *data,
+                    Some(InteractionData::MessageComponent(data)) => {
+                        let Some(opt_in) = consent_choice(&data.custom_id) else {
+                            bail!("unknown component: {}", data.custom_id);
+                        };
+                        return self.handle_consent(interaction, opt_in).await;
+                    }
                     _ => {
                         tracing::warn!("ignoring non-command interaction");
                         return Err(anyhow::format_err!("ignoring non-command interaction"));
@@ -126,9 +171,24 @@ impl Bot {
         interaction: Interaction,
         data: CommandData,
     ) -> anyhow::Result<()> {
+        // Consent is answered privately, without the public deferred response
+        if &*data.name == "activity-roles" {
+            let opt_in = ActivityRolesCommand::opt_in(data)?;
+            return self.handle_consent(interaction, opt_in).await;
+        }
+
         interaction_ack(&self.http_client, &interaction).await?;
         let response = match &*data.name {
-            "manage" => ManageCommand::handle(&interaction, data, &self.cache, &self.rules).await,
+            "manage" => {
+                ManageCommand::handle(
+                    &interaction,
+                    data,
+                    &self.http_client,
+                    &self.cache,
+                    &self.rules,
+                )
+                .await
+            }
             "storage" => {
                 StorageCommand::handle(data, &self.rules, self.github_config.as_ref()).await
             }
@@ -151,20 +211,64 @@ impl Bot {
     }
 }
 
+impl Bot {
+    /// Handles both `/activity-roles` and the consent panel buttons
+    pub async fn handle_consent(&self, interaction: Interaction, opt_in: bool) -> Result<()> {
+        let guild_id = interaction.guild_id.ok_or(anyhow::anyhow!("No guild id"))?;
+        let user_id = interaction
+            .author_id()
+            .ok_or(anyhow::anyhow!("No user id"))?;
+
+        set_member_consent(&self.rules, guild_id.get(), user_id.get(), opt_in).await;
+        tokio::spawn(save_current_db_to_file(self.rules.clone()));
+        tokio::spawn(refresh_member_roles(
+            self.http_client.clone(),
+            self.rules.clone(),
+            self.cache.clone(),
+            self.presence_update_tasks.clone(),
+            self.member_histories.clone(),
+            guild_id,
+            user_id,
+        ));
+
+        let content = match opt_in {
+            true => "You're opted in, your activity will be turned into roles",
+            false => "You're opted out, your activity roles will be removed",
+        };
+        interaction_ephemeral_reply(&self.http_client, &interaction, content).await
+    }
+}
+
 /// entry point for the shard to run, the "main" function
 pub async fn runner(mut shard: Shard, bot: Arc<Bot>) {
     // Event loop
     while let Some(item) = shard.next_event(EventTypeFlags::all()).await {
         tracing::info!(?item, shard = ?shard.id(), "Received Event");
 
-        if let Ok(event) = &item {
-            let event = event.clone();
-            let bot = bot.clone();
-            tokio::spawn(async move { bot.cache.update(&event) });
+        // Mutes and deafens don't move the member, only a new channel matters to voice
+        // conditions. It's compared with the cached one, so voice states are cached in order
+        let voice_channel_unchanged = match &item {
+            Ok(event @ Event::VoiceStateUpdate(voice_state_update)) => {
+                let cached_channel_id = voice_state_update.guild_id.and_then(|guild_id| {
+                    bot.cache
+                        .voice_state(voice_state_update.user_id, guild_id)
+                        .map(|voice_state| voice_state.channel_id())
+                });
+                bot.cache.update(event);
+                cached_channel_id == voice_state_update.channel_id
+            }
+            Ok(event) => {
+                let event = event.clone();
+                let bot = bot.clone();
+                tokio::spawn(async move { bot.cache.update(&event) });
+                false
+            }
+            Err(_) => false,
         };
 
         match item {
             Ok(Event::GatewayClose(_)) if SHUTDOWN.load(Ordering::Relaxed) => break,
+            Ok(Event::VoiceStateUpdate(_)) if voice_channel_unchanged => continue,
             Ok(event) => {
                 let bot = bot.clone();
                 tokio::spawn(async move { bot.process_event(event).await })
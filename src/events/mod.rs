@@ -1,5 +1,9 @@
 mod lazy_null;
 mod presence;
+mod schedule;
+mod streaming;
 
 pub use lazy_null::*;
 pub use presence::*;
+pub use schedule::*;
+pub use streaming::*;
@@ -1,7 +1,13 @@
-use crate::{event_handler::DEBOUNCE_DELAY, rules_handler::GuildRules};
+use crate::{
+    event_handler::DEBOUNCE_DELAY,
+    events::announce_stream,
+    rules_handler::{GuildRules, MemberInfo, RoleType, UserPresence},
+};
+use chrono::{DateTime, Utc};
 use std::{
     collections::{BTreeMap, BTreeSet, HashMap},
     sync::Arc,
+    time::Duration,
 };
 use tokio::{
     sync::{Mutex, RwLock},
@@ -11,48 +17,114 @@ use tokio::{
 use twilight_cache_inmemory::InMemoryCache;
 use twilight_http::Client;
 use twilight_model::{
-    gateway::presence::{Activity, ActivityType},
+    gateway::presence::Activity,
     id::{
         Id,
         marker::{GuildMarker, RoleMarker, UserMarker},
     },
 };
 
+/// What the bot remembers about a member's managed roles between presence updates
+#[derive(Debug, Clone, Default)]
+pub struct MemberRoleHistory {
+    /// When each lingering role's rule first stopped matching, the linger is counted from there
+    pub stopped_matching_at: BTreeMap<u64, DateTime<Utc>>,
+    /// When the last announced stream of every streaming role started, so a stream that drops
+    /// and comes back isn't announced twice
+    pub announced_streams: BTreeMap<u64, u64>,
+}
+
+impl MemberRoleHistory {
+    /// Records the stream as announced for the role, false if it already was. Streams are told
+    /// apart by when they started
+    pub fn announce_stream(&mut self, role_id: u64, stream: &Activity) -> bool {
+        let Some(created_at) = stream.created_at else {
+            return true;
+        };
+        self.announced_streams.insert(role_id, created_at) != Some(created_at)
+    }
+}
+
 pub struct RolesToChange {
     pub roles_to_add: BTreeSet<Id<RoleMarker>>,
     pub roles_to_remove: BTreeSet<Id<RoleMarker>>,
+    /// When to look at the presence again, for rules still waiting on a minimum session or
+    /// roles lingering before removal
+    pub recheck_in: Option<Duration>,
 }
 
+/// `eligible_rules` are the guild's rules the member is eligible for, roles of any other managed
+/// rule are removed
 pub fn roles_for_activity(
-    guild_rules: GuildRules,
-    user_roles: BTreeSet<Id<RoleMarker>>,
-    user_activities: BTreeSet<String>,
+    guild_rules: &GuildRules,
+    eligible_rules: &GuildRules,
+    member: &MemberInfo,
+    user_presence: UserPresence,
+    history: &mut MemberRoleHistory,
+    now: DateTime<Utc>,
 ) -> Option<RolesToChange> {
     let managed_roles: BTreeSet<u64> = guild_rules.all_rules().iter().map(|r| r.role_id).collect();
+    // Rules outside their schedule are left out like ineligible ones, so their roles go away
+    let eligible_rules = &eligible_rules.active_rules(now);
+    let user_presence = eligible_rules.with_aliases(&user_presence);
 
-    let rules_to_assign = guild_rules.matching_rules(user_activities);
+    let mut rules_to_assign = eligible_rules.matching_rules(&user_presence, member, now);
+    if let Some(idle_rule) = eligible_rules.matching_idle_rule(&user_presence) {
+        rules_to_assign.insert(idle_rule.clone());
+    }
 
     let roles_ids_to_assign: BTreeSet<u64> =
         rules_to_assign.iter().map(|rule| rule.role_id).collect();
 
-    let user_roles: BTreeSet<u64> = user_roles
+    let user_roles: BTreeSet<u64> = member
+        .roles
         .iter()
-        .map(|role_id| role_id.get())
         .filter(|r| managed_roles.contains(r))
+        .cloned()
         .collect();
 
     let roles_to_add = roles_ids_to_assign
         .difference(&user_roles)
         .map(|id| Id::new(*id))
         .collect();
-    let roles_to_remove = user_roles
-        .difference(&roles_ids_to_assign)
-        .map(|id| Id::new(*id))
-        .collect();
+
+    // Lingering roles are kept until their rule's linger has passed since it first stopped
+    // matching, matching again cancels the removal. Roles the member is no longer eligible for
+    // don't linger
+    let mut roles_to_remove = BTreeSet::new();
+    let mut lingering = BTreeMap::new();
+    for role_id in user_roles.difference(&roles_ids_to_assign) {
+        let Some(rule) = eligible_rules.get_rule(*role_id) else {
+            roles_to_remove.insert(Id::new(*role_id));
+            continue;
+        };
+        let stopped_matching_at = *history.stopped_matching_at.entry(*role_id).or_insert(now);
+        let lingered = (now - stopped_matching_at).to_std().unwrap_or_default();
+        match Duration::from_secs(rule.linger_secs).checked_sub(lingered) {
+            Some(linger_left) if !linger_left.is_zero() => {
+                lingering.insert(*role_id, linger_left);
+            }
+            _ => {
+                roles_to_remove.insert(Id::new(*role_id));
+            }
+        }
+    }
+    history
+        .stopped_matching_at
+        .retain(|role_id, _| lingering.contains_key(role_id));
+
+    let recheck_in = [
+        eligible_rules.pending_session(&user_presence, now),
+        lingering.into_values().min(),
+    ]
+    .into_iter()
+    .flatten()
+    .min();
 
     Some(RolesToChange {
         roles_to_add,
         roles_to_remove,
+        recheck_in,
     })
 }
 
@@ -60,26 +132,38 @@ pub async fn update_roles_by_activity(
     http_client: Arc<Client>,
     cache: Arc<InMemoryCache>,
     roles_rules: Arc<RwLock<BTreeMap<u64, GuildRules>>>,
+    member_histories: Arc<Mutex<HashMap<(Id<GuildMarker>, Id<UserMarker>), MemberRoleHistory>>>,
     guild_id: Id<GuildMarker>,
     user_id: Id<UserMarker>,
-    user_activities: BTreeSet<String>,
-) -> Option<()> {
-    let user_roles: BTreeSet<Id<RoleMarker>> = cache
-        .member(guild_id, user_id)?
-        .roles()
-        .iter()
-        .cloned()
-        .collect();
+    user_presence: UserPresence,
+) -> Option<Duration> {
+    let member = MemberInfo::from_cache(&cache, guild_id, user_id)?;
+    // Every rule is looked at as of the same moment
+    let now = Utc::now();
 
     let guild_rules = {
         let rules_reader = roles_rules.read().await;
         rules_reader.get(&guild_id.get()).cloned()
     }?;
+    // Ineligible members never gain managed roles and lose the ones they have
+    let eligible_rules = guild_rules.eligible_rules(&member, now);
 
     let RolesToChange {
         roles_to_add,
         roles_to_remove,
-    } = roles_for_activity(guild_rules, user_roles, user_activities)?;
+        recheck_in,
+    } = roles_for_activity(
+        &guild_rules,
+        &eligible_rules,
+        &member,
+        user_presence.clone(),
+        member_histories
+            .lock()
+            .await
+            .entry((guild_id, user_id))
+            .or_default(),
+        now,
+    )?;
 
     for role_id in roles_to_add {
         tracing::warn!("Assigning Role {role_id:?} to {user_id:?} in {guild_id:?}");
@@ -87,7 +171,27 @@ pub async fn update_roles_by_activity(
             .add_guild_member_role(guild_id, user_id, role_id)
             .await;
         if let Err(e) = r {
-            tracing::error!(?e, "Couldn't add role")
+            tracing::error!(?e, "Couldn't add role");
+            continue;
+        }
+
+        if let Some(rule) = guild_rules.get_rule(role_id.get())
+            && rule.role_type == RoleType::Streaming
+            && let Some(channel_id) = rule.announce_channel_id
+            && let Some(stream) = user_presence.activities.iter().find(|activity| {
+                rule.matches_activity(activity, guild_rules.settings().raw_activity_names)
+            })
+            && member_histories
+                .lock()
+                .await
+                .entry((guild_id, user_id))
+                .or_default()
+                .announce_stream(rule.role_id, stream)
+        {
+            let r = announce_stream(&http_client, Id::new(channel_id), user_id, stream).await;
+            if let Err(e) = r {
+                tracing::error!(?e, "Couldn't announce stream")
+            }
         }
     }
 
@@ -102,27 +206,20 @@ pub async fn update_roles_by_activity(
         }
     }
 
-    None
-}
-
-pub fn user_activities_from_presence<'a, T: Iterator<Item = &'a Activity>>(
-    activities: T,
-) -> BTreeSet<String> {
-    activities
-        .filter(|activity| activity.kind == ActivityType::Playing)
-        .map(|activity| activity.name.to_string())
-        .collect()
+    recheck_in
 }
 
 /// the actual logic to change roles for users based on presence
+#[allow(clippy::too_many_arguments)]
 pub async fn handle_presence_update(
     http_client: Arc<Client>,
     rules: Arc<RwLock<BTreeMap<u64, GuildRules>>>,
     cache: Arc<InMemoryCache>,
     presence_update_tasks: Arc<Mutex<HashMap<(Id<GuildMarker>, Id<UserMarker>), JoinHandle<()>>>>,
+    member_histories: Arc<Mutex<HashMap<(Id<GuildMarker>, Id<UserMarker>), MemberRoleHistory>>>,
     guild_id: Id<GuildMarker>,
     user_id: Id<UserMarker>,
-    user_activities: BTreeSet<String>,
+    user_presence: UserPresence,
 ) {
     // Cancel existing task if exists
     let key = (guild_id, user_id);
@@ -130,18 +227,227 @@ pub async fn handle_presence_update(
     if let Some(task) = tasks.remove(&key) {
         task.abort();
     }
-    let future = update_roles_by_activity(
-        http_client.clone(),
-        cache.clone(),
-        rules.clone(),
-        guild_id,
-        user_id,
-        user_activities,
-    );
-    let task_handle = tokio::spawn(async {
+    let task_handle = tokio::spawn(async move {
         sleep(DEBOUNCE_DELAY).await;
-        future.await;
+        // Rules with a minimum session are granted once it's met and lingering roles are removed
+        // once their linger is over, unless a newer presence update replaces this task first
+        while let Some(recheck_in) = update_roles_by_activity(
+            http_client.clone(),
+            cache.clone(),
+            rules.clone(),
+            member_histories.clone(),
+            guild_id,
+            user_id,
+            user_presence.clone(),
+        )
+        .await
+        {
+            sleep(recheck_in).await;
+        }
     });
 
     tasks.insert(key, task_handle);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rules_handler::{ClientPlatform, Eligibility, Rule};
+    use twilight_model::gateway::presence::{ActivityType, MinimalActivity, Status};
+
+    fn activity_of_kind(name: &str, kind: ActivityType) -> Activity {
+        MinimalActivity {
+            kind,
+            name: name.to_string(),
+            url: None,
+        }
+        .into()
+    }
+
+    fn stream(created_at: Option<u64>) -> Activity {
+        let mut stream = activity_of_kind("Twitch", ActivityType::Streaming);
+        stream.created_at = created_at;
+        stream
+    }
+
+    fn presence(activities: Vec<Activity>, status: Status) -> UserPresence {
+        UserPresence {
+            activities,
+            status,
+            platforms: [ClientPlatform::Desktop].into(),
+        }
+    }
+
+    fn member(roles: &[u64]) -> MemberInfo {
+        MemberInfo {
+            roles: roles.iter().cloned().collect(),
+            ..Default::default()
+        }
+    }
+
+    fn role_ids(ids: &[u64]) -> BTreeSet<Id<RoleMarker>> {
+        ids.iter().map(|id| Id::new(*id)).collect()
+    }
+
+    #[test]
+    fn test_idle_and_offline() {
+        let mut guild_rules = GuildRules::new();
+        guild_rules
+            .add_rule(Rule::new(
+                0,
+                "guild_name".to_string(),
+                1,
+                "idle".to_string(),
+                RoleType::Idle,
+            ))
+            .unwrap();
+        guild_rules
+            .add_rule(Rule {
+                activities: ["quake".to_string()].into(),
+                ..Rule::new(
+                    0,
+                    "guild_name".to_string(),
+                    2,
+                    "quake".to_string(),
+                    RoleType::NamedActivity,
+                )
+            })
+            .unwrap();
+
+        let changes = |member: &MemberInfo, user_presence: UserPresence| {
+            roles_for_activity(
+                &guild_rules,
+                &guild_rules,
+                member,
+                user_presence,
+                &mut MemberRoleHistory::default(),
+                Utc::now(),
+            )
+            .unwrap()
+        };
+
+        // Online without a game gets the idle role
+        let online_idle = changes(&member(&[]), presence(vec![], Status::Online));
+        assert_eq!(online_idle.roles_to_add, role_ids(&[1]));
+        assert!(online_idle.roles_to_remove.is_empty());
+
+        // Starting a game swaps it for the game's role
+        let quake = activity_of_kind("Quake", ActivityType::Playing);
+        let playing = changes(&member(&[1]), presence(vec![quake], Status::Online));
+        assert_eq!(playing.roles_to_add, role_ids(&[2]));
+        assert_eq!(playing.roles_to_remove, role_ids(&[1]));
+
+        // Going offline strips the idle role without granting anything
+        let offline = changes(&member(&[1]), UserPresence::offline());
+        assert!(offline.roles_to_add.is_empty());
+        assert_eq!(offline.roles_to_remove, role_ids(&[1]));
+        assert_eq!(offline.recheck_in, None);
+    }
+
+    #[test]
+    fn test_stream_announced_once() {
+        let mut history = MemberRoleHistory::default();
+        assert!(history.announce_stream(1, &stream(Some(1000))));
+        assert!(!history.announce_stream(1, &stream(Some(1000))));
+        assert!(history.announce_stream(2, &stream(Some(1000))));
+        assert!(history.announce_stream(1, &stream(Some(2000))));
+        assert!(history.announce_stream(1, &stream(None)));
+    }
+
+    #[test]
+    fn test_linger() {
+        let mut guild_rules = GuildRules::new();
+        guild_rules
+            .add_rule(Rule {
+                activities: ["quake".to_string()].into(),
+                linger_secs: 60,
+                ..Rule::new(
+                    0,
+                    "guild_name".to_string(),
+                    1,
+                    "quake".to_string(),
+                    RoleType::NamedActivity,
+                )
+            })
+            .unwrap();
+        let member = member(&[1]);
+        let mut history = MemberRoleHistory::default();
+        let stopped_at = Utc::now();
+
+        let mut changes = |user_presence: UserPresence, secs_later: i64| {
+            roles_for_activity(
+                &guild_rules,
+                &guild_rules,
+                &member,
+                user_presence,
+                &mut history,
+                stopped_at + chrono::Duration::seconds(secs_later),
+            )
+            .unwrap()
+        };
+
+        // The linger starts when the rule stops matching, later updates don't restart it
+        let stopped = changes(presence(vec![], Status::Online), 0);
+        assert!(stopped.roles_to_remove.is_empty());
+        assert_eq!(stopped.recheck_in, Some(Duration::from_secs(60)));
+        let still_lingering = changes(presence(vec![], Status::Idle), 10);
+        assert!(still_lingering.roles_to_remove.is_empty());
+        assert_eq!(still_lingering.recheck_in, Some(Duration::from_secs(50)));
+        let lingered = changes(presence(vec![], Status::Online), 60);
+        assert_eq!(lingered.roles_to_remove, role_ids(&[1]));
+        assert_eq!(lingered.recheck_in, None);
+
+        // Matching again cancels the linger, the next stop starts a new one
+        let quake = activity_of_kind("Quake", ActivityType::Playing);
+        assert!(
+            changes(presence(vec![quake], Status::Online), 70)
+                .roles_to_remove
+                .is_empty()
+        );
+        let stopped_again = changes(presence(vec![], Status::Online), 80);
+        assert!(stopped_again.roles_to_remove.is_empty());
+        assert_eq!(stopped_again.recheck_in, Some(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn test_ineligible_roles_dont_linger() {
+        let mut guild_rules = GuildRules::new();
+        guild_rules
+            .add_rule(Rule {
+                activities: ["quake".to_string()].into(),
+                linger_secs: 60,
+                eligibility: Eligibility {
+                    required_roles: [10].into(),
+                    ..Default::default()
+                },
+                ..Rule::new(
+                    0,
+                    "guild_name".to_string(),
+                    1,
+                    "quake".to_string(),
+                    RoleType::NamedActivity,
+                )
+            })
+            .unwrap();
+
+        let changes = |member: &MemberInfo| {
+            roles_for_activity(
+                &guild_rules,
+                &guild_rules.eligible_rules(member, Utc::now()),
+                member,
+                presence(vec![], Status::Online),
+                &mut MemberRoleHistory::default(),
+                Utc::now(),
+            )
+            .unwrap()
+        };
+
+        let eligible = changes(&member(&[1, 10]));
+        assert!(eligible.roles_to_remove.is_empty());
+        assert_eq!(eligible.recheck_in, Some(Duration::from_secs(60)));
+
+        let ineligible = changes(&member(&[1]));
+        assert_eq!(ineligible.roles_to_remove, role_ids(&[1]));
+        assert_eq!(ineligible.recheck_in, None);
+    }
+}
@@ -0,0 +1,209 @@
+use crate::{
+    discord_utils::{refresh_member_roles, remove_role_from_members},
+    events::MemberRoleHistory,
+    rules_handler::{GuildRules, Rule, save_current_db_to_file},
+};
+use chrono::{DateTime, Utc};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    sync::{Mutex, RwLock},
+    task::JoinHandle,
+    time::{MissedTickBehavior, interval},
+};
+use twilight_cache_inmemory::InMemoryCache;
+use twilight_http::Client;
+use twilight_model::id::{
+    Id,
+    marker::{GuildMarker, UserMarker},
+};
+
+/// Schedule windows and expiry are minute precise
+const SCHEDULE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// An expired rule whose role couldn't be taken from everyone is archived anyway after this many
+/// tries
+const MAX_ROLE_REMOVAL_ATTEMPTS: u32 = 5;
+
+/// Each retry of an expired rule's role removal waits twice as long as the one before, every try
+/// fetches all of the guild's members
+fn next_removal_attempt(attempts: u32, now: DateTime<Utc>) -> DateTime<Utc> {
+    now + SCHEDULE_CHECK_INTERVAL * 2u32.pow(attempts)
+}
+
+/// The guilds whose schedule state differs from the last check, guilds that weren't there yet
+/// are left alone
+fn changed_guilds(
+    previous_states: &BTreeMap<u64, BTreeSet<(u64, Vec<bool>)>>,
+    current_states: &BTreeMap<u64, BTreeSet<(u64, Vec<bool>)>>,
+) -> Vec<u64> {
+    current_states
+        .iter()
+        .filter(|(guild_id, state)| {
+            previous_states
+                .get(guild_id)
+                .is_some_and(|previous_state| previous_state != *state)
+        })
+        .map(|(guild_id, _)| *guild_id)
+        .collect()
+}
+
+/// Refreshes a guild's members whenever one of its rules enters or leaves its schedule window,
+/// or a time window in a rule's condition opens or closes, so roles follow the schedule without
+/// waiting for presence changes. Expired rules are archived and their role is taken from everyone
+pub async fn watch_rule_schedules(
+    http_client: Arc<Client>,
+    rules: Arc<RwLock<BTreeMap<u64, GuildRules>>>,
+    cache: Arc<InMemoryCache>,
+    presence_update_tasks: Arc<Mutex<HashMap<(Id<GuildMarker>, Id<UserMarker>), JoinHandle<()>>>>,
+    member_histories: Arc<Mutex<HashMap<(Id<GuildMarker>, Id<UserMarker>), MemberRoleHistory>>>,
+) {
+    let mut schedule_states: BTreeMap<u64, BTreeSet<(u64, Vec<bool>)>> = BTreeMap::new();
+    // Expired rules whose role removal failed, by guild and role, with the tries so far and when
+    // to try again
+    let mut removal_retries: HashMap<(u64, u64), (u32, DateTime<Utc>)> = HashMap::new();
+    let mut check_interval = interval(SCHEDULE_CHECK_INTERVAL);
+    // Removing an expired rule's role can take longer than a check interval
+    check_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        check_interval.tick().await;
+
+        let now = Utc::now();
+        let expired_rules: Vec<Rule> = rules
+            .read()
+            .await
+            .values()
+            .flat_map(|guild_rules| guild_rules.expired_rules(now))
+            .collect();
+        // Rules that aren't expired anymore were changed or removed meanwhile
+        removal_retries.retain(|(guild_id, role_id), _| {
+            expired_rules
+                .iter()
+                .any(|rule| rule.guild_id == *guild_id && rule.role_id == *role_id)
+        });
+        let mut archived_any = false;
+        // The rule is only archived once its role is gone from everyone, a failed removal is
+        // tried again later until it runs out of attempts
+        for rule in expired_rules {
+            let key = (rule.guild_id, rule.role_id);
+            if removal_retries
+                .get(&key)
+                .is_some_and(|(_, next_attempt)| *next_attempt > now)
+            {
+                continue;
+            }
+
+            tracing::info!(rule.role_name, rule.guild_name, "Rule expired, archiving");
+            let removed = remove_role_from_members(
+                &http_client,
+                Id::new(rule.guild_id),
+                Id::new(rule.role_id),
+            )
+            .await;
+            let done = match removed {
+                Ok(failed) if failed.is_empty() => true,
+                Ok(failed) => {
+                    tracing::error!(
+                        ?failed,
+                        "Couldn't remove the expired rule's role from everyone"
+                    );
+                    false
+                }
+                Err(e) => {
+                    tracing::error!(?e, "Couldn't remove the expired rule's role");
+                    false
+                }
+            };
+            let attempts = removal_retries
+                .get(&key)
+                .map_or(0, |(attempts, _)| *attempts)
+                + 1;
+            if !done {
+                if attempts < MAX_ROLE_REMOVAL_ATTEMPTS {
+                    removal_retries.insert(key, (attempts, next_removal_attempt(attempts, now)));
+                    continue;
+                }
+                tracing::error!(
+                    rule.role_name,
+                    rule.guild_name,
+                    attempts,
+                    "Giving up on removing the expired rule's role, archiving it anyway"
+                );
+            }
+            removal_retries.remove(&key);
+
+            if let Some(guild_rules) = rules.write().await.get_mut(&rule.guild_id)
+                && guild_rules.archive_rule(rule.role_id).is_ok()
+            {
+                archived_any = true;
+            }
+        }
+        if archived_any {
+            tokio::spawn(save_current_db_to_file(rules.clone()));
+        }
+
+        let current_states: BTreeMap<u64, BTreeSet<(u64, Vec<bool>)>> = rules
+            .read()
+            .await
+            .iter()
+            .map(|(guild_id, guild_rules)| (*guild_id, guild_rules.schedule_state(now)))
+            .collect();
+
+        for guild_id in changed_guilds(&schedule_states, &current_states) {
+            let guild_id = Id::new(guild_id);
+            let user_ids: Vec<Id<UserMarker>> = cache
+                .guild_members(guild_id)
+                .map(|members| members.iter().cloned().collect())
+                .unwrap_or_default();
+            tracing::info!(?guild_id, "Rule schedule changed, refreshing members");
+
+            for user_id in user_ids {
+                tokio::spawn(refresh_member_roles(
+                    http_client.clone(),
+                    rules.clone(),
+                    cache.clone(),
+                    presence_update_tasks.clone(),
+                    member_histories.clone(),
+                    guild_id,
+                    user_id,
+                ));
+            }
+        }
+
+        schedule_states = current_states;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_changed_guilds() {
+        let previous_states = BTreeMap::from([
+            (1, BTreeSet::from([(10, vec![])])),
+            (2, BTreeSet::from([(20, vec![true])])),
+        ]);
+        let current_states = BTreeMap::from([
+            (1, BTreeSet::from([(10, vec![])])),
+            (2, BTreeSet::from([(20, vec![false])])),
+            (3, BTreeSet::from([(30, vec![])])),
+        ]);
+
+        assert_eq!(changed_guilds(&previous_states, &current_states), vec![2]);
+        assert!(changed_guilds(&BTreeMap::new(), &current_states).is_empty());
+    }
+
+    #[test]
+    fn test_next_removal_attempt() {
+        let now = Utc::now();
+        let delays: Vec<i64> = (1..MAX_ROLE_REMOVAL_ATTEMPTS)
+            .map(|attempts| (next_removal_attempt(attempts, now) - now).num_minutes())
+            .collect();
+        assert_eq!(delays, vec![2, 4, 8, 16]);
+    }
+}
@@ -0,0 +1,39 @@
+use anyhow::Result;
+use twilight_http::Client;
+use twilight_mention::Mention;
+use twilight_model::{
+    gateway::presence::Activity,
+    id::{
+        Id,
+        marker::{ChannelMarker, UserMarker},
+    },
+};
+use twilight_util::builder::embed::{EmbedBuilder, EmbedFieldBuilder};
+
+pub const STREAM_EMBED_COLOR: u32 = 0x9146ff;
+
+pub async fn announce_stream(
+    http_client: &Client,
+    channel_id: Id<ChannelMarker>,
+    user_id: Id<UserMarker>,
+    stream: &Activity,
+) -> Result<()> {
+    let mut embed = EmbedBuilder::new()
+        .color(STREAM_EMBED_COLOR)
+        .title(stream.details.clone().unwrap_or("Live Now".to_string()))
+        .description(format!("{} is live on {}", user_id.mention(), stream.name));
+
+    if let Some(url) = &stream.url {
+        embed = embed.url(url);
+    }
+    if let Some(game) = &stream.state {
+        embed = embed.field(EmbedFieldBuilder::new("Playing", game).inline());
+    }
+
+    http_client
+        .create_message(channel_id)
+        .embeds(&[embed.build()])
+        .await?;
+
+    Ok(())
+}
@@ -1,20 +1,32 @@
 use crate::{
+    condition::Condition,
     config_handler::GithubConfig,
-    rules_handler::{self, GuildRules, RoleType, Rule},
+    interactions::consent::post_consent_panel,
+    rules_handler::{
+        self, ActivityKind, ClientPlatform, EligibilityUpdate, GuildRules, ListeningField,
+        MatchMode, RoleType, Rule, RuleUpdate, ScheduleWindow, StatusKind,
+    },
 };
 use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
 use std::{
     collections::{BTreeMap, BTreeSet},
     sync::Arc,
 };
 use tokio::sync::RwLock;
 use twilight_cache_inmemory::InMemoryCache;
+use twilight_http::Client;
 use twilight_interactions::command::{CommandModel, CommandOption, CreateCommand, CreateOption};
 use twilight_model::{
     application::interaction::{Interaction, application_command::CommandData},
     channel::message::embed::EmbedField,
     guild::Role,
     http::interaction::InteractionResponseData,
+    id::{
+        Id,
+        marker::{ChannelMarker, RoleMarker},
+    },
 };
 use twilight_util::builder::{InteractionResponseDataBuilder, embed::EmbedBuilder};
 
@@ -84,7 +96,8 @@ impl StorageCommand {
             }
             StorageCommandOptions::LoadFromFile => {
                 let mut rules_writer = rules.write().await;
-                let rules = rules_handler::load_db_from_file()?;
+                let mut rules = rules_handler::load_db_from_file()?;
+                rules_handler::keep_consent(&rules_writer, &mut rules);
                 *rules_writer = rules.clone();
 
                 let embeds = rules.iter().map(|(guild_id, guild_rules)| {
@@ -105,10 +118,12 @@ impl StorageCommand {
             }
             StorageCommandOptions::LoadFromGithub => {
                 let mut rules_writer = rules.write().await;
-                let rules = rules_handler::load_rules_from_github(
+                let mut rules = rules_handler::load_rules_from_github(
                     github_config.ok_or(anyhow::anyhow!("No github config"))?,
                 )
                 .await?;
+                rules_handler::keep_consent(&rules_writer, &mut rules);
+                rules_handler::keep_guild_data(&rules_writer, &mut rules);
                 *rules_writer = rules.clone();
 
                 let embeds = rules.iter().map(|(guild_id, guild_rules)| {
@@ -169,6 +184,33 @@ pub enum ManageCommand {
 
     #[command(name = "list")]
     List(ListRoleRule),
+
+    #[command(name = "rich-presence")]
+    RichPresence(RichPresenceRoleRule),
+
+    #[command(name = "eligibility")]
+    Eligibility(EligibilityRoleRule),
+
+    #[command(name = "consent")]
+    Consent(ConsentSettings),
+
+    #[command(name = "schedule")]
+    Schedule(ScheduleRoleRule),
+
+    #[command(name = "catalog")]
+    Catalog(CatalogCommand),
+
+    #[command(name = "fuzzy")]
+    Fuzzy(FuzzyRoleRule),
+
+    #[command(name = "normalization")]
+    Normalization(NormalizationSettings),
+
+    #[command(name = "alias")]
+    Alias(AliasCommand),
+
+    #[command(name = "condition")]
+    Condition(ConditionRoleRule),
 }
 
 impl ManageCommand {
@@ -181,6 +223,7 @@ impl ManageCommand {
     pub async fn handle(
         interaction: &Interaction,
         data: CommandData,
+        http_client: &Client,
         cache: &Arc<InMemoryCache>,
         rules: &Arc<RwLock<BTreeMap<u64, GuildRules>>>,
     ) -> Result<Option<InteractionResponseData>> {
@@ -194,6 +237,15 @@ impl ManageCommand {
             ManageCommand::Remove(command) => command.run(interaction, rules).await,
             ManageCommand::Edit(command) => command.run(interaction, rules).await,
             ManageCommand::List(command) => command.run(interaction, rules).await,
+            ManageCommand::RichPresence(command) => command.run(interaction, rules).await,
+            ManageCommand::Eligibility(command) => command.run(interaction, rules).await,
+            ManageCommand::Consent(command) => command.run(interaction, http_client, rules).await,
+            ManageCommand::Schedule(command) => command.run(interaction, rules).await,
+            ManageCommand::Catalog(command) => command.run(interaction, rules).await,
+            ManageCommand::Fuzzy(command) => command.run(interaction, rules).await,
+            ManageCommand::Normalization(command) => command.run(interaction, rules).await,
+            ManageCommand::Alias(command) => command.run(interaction, rules).await,
+            ManageCommand::Condition(command) => command.run(interaction, rules).await,
         }
     }
 }
@@ -207,6 +259,64 @@ pub struct AddRoleRule {
     #[command(desc = "Type")]
     pub role_type: RoleType,
 
+    #[command(desc = "How activities are matched, defaults to substring")]
+    pub match_mode: Option<MatchMode>,
+
+    #[command(
+        min_value = 0,
+        max_value = 100,
+        desc = "Fuzzy matching only, similarity in percent an activity needs, 0 for the default 80"
+    )]
+    pub fuzzy_threshold: Option<i64>,
+
+    #[command(desc = "Activity type the rule applies to, defaults to Playing")]
+    pub activity_type: Option<ActivityKind>,
+
+    #[command(desc = "Only match activities reported by a Discord application")]
+    pub verified_only: Option<bool>,
+
+    #[command(desc = "Priority within the exclusive group, higher wins, defaults to 0")]
+    pub priority: Option<i64>,
+
+    #[command(desc = "Exclusive group, only the highest priority matching rule in it is assigned")]
+    pub exclusive_group: Option<String>,
+
+    #[command(
+        desc = "Rule set, each set is evaluated on its own with its own Otherwise role, defaults to `main`"
+    )]
+    pub rule_set: Option<String>,
+
+    #[command(desc = "Streaming roles only, channel to post go-live announcements in")]
+    pub announce_channel: Option<Id<ChannelMarker>>,
+
+    #[command(desc = "Listening roles only, match artist, track or both, defaults to both")]
+    pub listening_field: Option<ListeningField>,
+
+    #[command(
+        desc = "Only applies while online/idle/dnd/offline, `;` separated, grants Status roles"
+    )]
+    pub statuses: Option<String>,
+
+    #[command(
+        desc = "Only applies on desktop/mobile/web, `;` separated, grants Client Platform roles"
+    )]
+    pub platforms: Option<String>,
+
+    #[command(
+        min_value = 0,
+        desc = "Only grant the role after playing for this many seconds"
+    )]
+    pub min_session_seconds: Option<i64>,
+
+    #[command(
+        min_value = 0,
+        desc = "Keep the role for this many seconds after the activity stops"
+    )]
+    pub linger_seconds: Option<i64>,
+
+    #[command(desc = "Retire the rule at `2025-06-01 18:00` guild time, archiving it")]
+    pub expires_at: Option<String>,
+
     #[command(desc = "Comment")]
     pub comment: Option<String>,
 }
@@ -219,18 +329,36 @@ impl AddRoleRule {
         rules: &Arc<RwLock<BTreeMap<u64, GuildRules>>>,
     ) -> Result<Option<InteractionResponseData>> {
         let guild_id = interaction.guild_id.ok_or(anyhow::anyhow!("No guild id"))?;
+        let guild_name = cache
+            .guild(guild_id)
+            .ok_or(anyhow::anyhow!("No guild"))?
+            .name()
+            .to_string();
         let new_rule = Rule {
-            guild_id: guild_id.into(),
-            guild_name: cache
-                .guild(guild_id)
-                .ok_or(anyhow::anyhow!("No guild"))?
-                .name()
-                .to_string(),
-            role_id: self.role_tag.id.get(),
-            role_name: self.role_tag.name.clone(),
-            role_type: self.role_type.clone(),
-            activities: BTreeSet::new(),
+            match_mode: self.match_mode.clone().unwrap_or_default(),
+            fuzzy_threshold: parse_fuzzy_threshold(self.fuzzy_threshold)?.flatten(),
+            activity_types: self.activity_type.iter().cloned().collect(),
+            verified_only: self.verified_only.unwrap_or(false),
+            priority: self.priority.unwrap_or(0),
+            exclusive_group: self.exclusive_group.clone(),
+            rule_set: parse_rule_set(&self.rule_set).unwrap_or_default(),
+            announce_channel_id: self.announce_channel.map(|channel_id| channel_id.get()),
+            listening_field: self.listening_field.clone().unwrap_or_default(),
+            statuses: parse_statuses(&self.statuses)?.unwrap_or_default(),
+            platforms: parse_platforms(&self.platforms)?.unwrap_or_default(),
+            min_session_secs: non_negative_option(self.min_session_seconds)?.unwrap_or(0),
+            linger_secs: non_negative_option(self.linger_seconds)?.unwrap_or(0),
+            expires_at: parse_expiry(rules, guild_id.get(), &self.expires_at)
+                .await?
+                .flatten(),
             comments: self.comment.clone().unwrap_or("".to_string()),
+            ..Rule::new(
+                guild_id.into(),
+                guild_name,
+                self.role_tag.id.get(),
+                self.role_tag.name.clone(),
+                self.role_type.clone(),
+            )
         };
         let mut rules_writer = rules.write().await;
         rules_writer
@@ -288,6 +416,77 @@ pub struct EditRoleRule {
     #[command(desc = "Remove Activities, `;` separated")]
     pub remove_activities: Option<String>,
 
+    #[command(desc = "Add Exclusions, activities containing them never match, `;` separated")]
+    pub add_exclusions: Option<String>,
+
+    #[command(desc = "Remove Exclusions, `;` separated")]
+    pub remove_exclusions: Option<String>,
+
+    #[command(desc = "Add Discord Application IDs, `;` separated")]
+    pub add_application_ids: Option<String>,
+
+    #[command(desc = "Remove Discord Application IDs, `;` separated")]
+    pub remove_application_ids: Option<String>,
+
+    #[command(desc = "Add an activity type the rule applies to")]
+    pub add_activity_type: Option<ActivityKind>,
+
+    #[command(desc = "Remove an activity type, a rule without types applies to Playing")]
+    pub remove_activity_type: Option<ActivityKind>,
+
+    #[command(desc = "Only match activities reported by a Discord application")]
+    pub verified_only: Option<bool>,
+
+    #[command(desc = "How activities are matched")]
+    pub match_mode: Option<MatchMode>,
+
+    #[command(desc = "Priority within the exclusive group, higher wins")]
+    pub priority: Option<i64>,
+
+    #[command(desc = "Exclusive group, only the highest priority matching rule in it is assigned")]
+    pub exclusive_group: Option<String>,
+
+    #[command(desc = "Take the rule out of its exclusive group")]
+    pub clear_exclusive_group: Option<bool>,
+
+    #[command(
+        desc = "Attach activity catalogs, `;` separated, replaces the current ones, `none` clears"
+    )]
+    pub catalogs: Option<String>,
+
+    #[command(desc = "Move the rule to another rule set, `main` for the guild's main one")]
+    pub rule_set: Option<String>,
+
+    #[command(desc = "Streaming roles only, channel to post go-live announcements in")]
+    pub announce_channel: Option<Id<ChannelMarker>>,
+
+    #[command(desc = "Stop posting go-live announcements")]
+    pub clear_announce_channel: Option<bool>,
+
+    #[command(desc = "Listening roles only, match artist, track or both")]
+    pub listening_field: Option<ListeningField>,
+
+    #[command(desc = "Only applies while online/idle/dnd/offline, `;` separated, `any` clears")]
+    pub statuses: Option<String>,
+
+    #[command(desc = "Only applies on desktop/mobile/web, `;` separated, `any` clears")]
+    pub platforms: Option<String>,
+
+    #[command(
+        min_value = 0,
+        desc = "Only grant the role after playing for this many seconds, 0 clears"
+    )]
+    pub min_session_seconds: Option<i64>,
+
+    #[command(
+        min_value = 0,
+        desc = "Keep the role for this many seconds after the activity stops, 0 clears"
+    )]
+    pub linger_seconds: Option<i64>,
+
+    #[command(desc = "Retire the rule at `2025-06-01 18:00` guild time, `never` clears")]
+    pub expires_at: Option<String>,
+
     #[command(desc = "Comment")]
     pub comment: Option<String>,
 }
@@ -304,27 +503,88 @@ impl EditRoleRule {
             .get();
         let role_id = self.role_tag.id.get();
 
-        let add_activities = self.add_activities.clone().unwrap_or("".to_string());
-        let remove_activities = self.remove_activities.clone().unwrap_or("".to_string());
-
-        let add_activities = add_activities
-            .split(";")
-            .map(|s| s.to_string())
-            .collect::<BTreeSet<String>>();
-        let remove_activities = remove_activities
-            .split(";")
-            .map(|s| s.to_string())
-            .collect::<BTreeSet<String>>();
-
-        let role_rule = rules_handler::update_role_rule(
-            rules,
-            guild_id,
-            role_id,
-            add_activities,
-            remove_activities,
-            self.comment.clone().unwrap_or("".to_string()),
-        )
-        .await?;
+        let update = RuleUpdate {
+            add_activities: split_option_list(&self.add_activities),
+            remove_activities: split_option_list(&self.remove_activities),
+            add_exclusions: split_option_list(&self.add_exclusions),
+            remove_exclusions: split_option_list(&self.remove_exclusions),
+            add_application_ids: parse_application_ids(&self.add_application_ids)?,
+            remove_application_ids: parse_application_ids(&self.remove_application_ids)?,
+            add_activity_types: self.add_activity_type.iter().cloned().collect(),
+            remove_activity_types: self.remove_activity_type.iter().cloned().collect(),
+            verified_only: self.verified_only,
+            match_mode: self.match_mode.clone(),
+            priority: self.priority,
+            exclusive_group: match self.clear_exclusive_group {
+                Some(true) => Some(None),
+                _ => self.exclusive_group.clone().map(Some),
+            },
+            rule_set: parse_rule_set(&self.rule_set),
+            listening_field: self.listening_field.clone(),
+            statuses: parse_statuses(&self.statuses)?,
+            platforms: parse_platforms(&self.platforms)?,
+            min_session_secs: non_negative_option(self.min_session_seconds)?,
+            linger_secs: non_negative_option(self.linger_seconds)?,
+            expires_at: parse_expiry(rules, guild_id, &self.expires_at).await?,
+            announce_channel_id: match self.clear_announce_channel {
+                Some(true) => Some(None),
+                _ => self
+                    .announce_channel
+                    .map(|channel_id| Some(channel_id.get())),
+            },
+            comments: Some(self.comment.clone().unwrap_or("".to_string())),
+            catalogs: parse_catalogs(&self.catalogs),
+            ..Default::default()
+        };
+
+        let role_rule = rules_handler::update_role_rule(rules, guild_id, role_id, update).await?;
+
+        tokio::spawn(rules_handler::save_current_db_to_file(rules.clone()));
+
+        Ok(Some(rule_to_interaction_response_data(role_rule)))
+    }
+}
+
+#[derive(CommandModel, CreateCommand, Debug)]
+#[command(
+    name = "condition",
+    desc = "Grant a role rule while a condition holds, instead of matching its activities"
+)]
+pub struct ConditionRoleRule {
+    #[command(desc = "Role Tag")]
+    pub role_tag: Role,
+
+    #[command(desc = "e.g. `activity:tekken AND voice AND NOT status:dnd`, `none` clears")]
+    pub condition: String,
+}
+
+/// `none` clears the condition, so the rule matches its activities again
+fn parse_condition(condition: &str) -> Result<Option<Condition>> {
+    if condition.trim().eq_ignore_ascii_case("none") {
+        return Ok(None);
+    }
+
+    Ok(Some(Condition::parse(condition)?))
+}
+
+impl ConditionRoleRule {
+    pub async fn run(
+        &self,
+        interaction: &Interaction,
+        rules: &Arc<RwLock<BTreeMap<u64, GuildRules>>>,
+    ) -> Result<Option<InteractionResponseData>> {
+        let guild_id = interaction
+            .guild_id
+            .ok_or(anyhow::anyhow!("No guild id"))?
+            .get();
+
+        let update = RuleUpdate {
+            condition: Some(parse_condition(&self.condition)?),
+            ..Default::default()
+        };
+        let role_rule =
+            rules_handler::update_role_rule(rules, guild_id, self.role_tag.id.get(), update)
+                .await?;
 
         tokio::spawn(rules_handler::save_current_db_to_file(rules.clone()));
 
@@ -332,6 +592,689 @@ impl EditRoleRule {
     }
 }
 
+#[derive(CommandModel, CreateCommand, Debug)]
+#[command(
+    name = "rich-presence",
+    desc = "Edit Role Rule's rich presence conditions, all set conditions must hold"
+)]
+pub struct RichPresenceRoleRule {
+    #[command(desc = "Role Tag")]
+    pub role_tag: Role,
+
+    #[command(desc = "Add Details keywords, e.g. `In Ranked Match`, `;` separated")]
+    pub add_details: Option<String>,
+
+    #[command(desc = "Remove Details keywords, `;` separated")]
+    pub remove_details: Option<String>,
+
+    #[command(desc = "Add State keywords, `;` separated")]
+    pub add_state: Option<String>,
+
+    #[command(desc = "Remove State keywords, `;` separated")]
+    pub remove_state: Option<String>,
+
+    #[command(desc = "Add Large Image Text keywords, e.g. a map name, `;` separated")]
+    pub add_large_text: Option<String>,
+
+    #[command(desc = "Remove Large Image Text keywords, `;` separated")]
+    pub remove_large_text: Option<String>,
+
+    #[command(desc = "Minimal party size, 0 removes the limit", min_value = 0)]
+    pub min_party_size: Option<i64>,
+
+    #[command(desc = "Maximal party size, 0 removes the limit", min_value = 0)]
+    pub max_party_size: Option<i64>,
+}
+
+/// Zero means no limit
+fn party_size_limit(option: Option<i64>) -> Option<Option<u64>> {
+    option.map(|size| match size {
+        0 => None,
+        size => Some(size as u64),
+    })
+}
+
+impl RichPresenceRoleRule {
+    pub async fn run(
+        &self,
+        interaction: &Interaction,
+        rules: &Arc<RwLock<BTreeMap<u64, GuildRules>>>,
+    ) -> Result<Option<InteractionResponseData>> {
+        let guild_id = interaction
+            .guild_id
+            .ok_or(anyhow::anyhow!("No guild id"))?
+            .get();
+        let role_id = self.role_tag.id.get();
+
+        let update = RuleUpdate {
+            add_details: split_option_list(&self.add_details),
+            remove_details: split_option_list(&self.remove_details),
+            add_state: split_option_list(&self.add_state),
+            remove_state: split_option_list(&self.remove_state),
+            add_large_text: split_option_list(&self.add_large_text),
+            remove_large_text: split_option_list(&self.remove_large_text),
+            min_party_size: party_size_limit(self.min_party_size),
+            max_party_size: party_size_limit(self.max_party_size),
+            ..Default::default()
+        };
+
+        let role_rule = rules_handler::update_role_rule(rules, guild_id, role_id, update).await?;
+
+        tokio::spawn(rules_handler::save_current_db_to_file(rules.clone()));
+
+        Ok(Some(rule_to_interaction_response_data(role_rule)))
+    }
+}
+
+#[derive(CommandModel, CreateCommand, Debug)]
+#[command(
+    name = "eligibility",
+    desc = "Set who rules apply to, for a role rule or for the whole guild if no role tag is provided"
+)]
+pub struct EligibilityRoleRule {
+    #[command(desc = "Role Tag")]
+    pub role_tag: Option<Role>,
+
+    #[command(desc = "Only members with one of the required roles are eligible")]
+    pub add_required_role: Option<Id<RoleMarker>>,
+
+    #[command(desc = "Remove a required role")]
+    pub remove_required_role: Option<Id<RoleMarker>>,
+
+    #[command(desc = "Members with an excluded role are not eligible")]
+    pub add_excluded_role: Option<Id<RoleMarker>>,
+
+    #[command(desc = "Remove an excluded role")]
+    pub remove_excluded_role: Option<Id<RoleMarker>>,
+
+    #[command(desc = "Skip bot accounts")]
+    pub skip_bots: Option<bool>,
+
+    #[command(
+        desc = "Days a member has to be in the guild for, 0 removes the limit",
+        min_value = 0
+    )]
+    pub min_member_days: Option<i64>,
+}
+
+impl EligibilityRoleRule {
+    pub async fn run(
+        &self,
+        interaction: &Interaction,
+        rules: &Arc<RwLock<BTreeMap<u64, GuildRules>>>,
+    ) -> Result<Option<InteractionResponseData>> {
+        let guild_id = interaction
+            .guild_id
+            .ok_or(anyhow::anyhow!("No guild id"))?
+            .get();
+        let role_ids = |role_id: &Option<Id<RoleMarker>>| -> BTreeSet<u64> {
+            role_id.iter().map(|role_id| role_id.get()).collect()
+        };
+
+        let eligibility = EligibilityUpdate {
+            add_required_roles: role_ids(&self.add_required_role),
+            remove_required_roles: role_ids(&self.remove_required_role),
+            add_excluded_roles: role_ids(&self.add_excluded_role),
+            remove_excluded_roles: role_ids(&self.remove_excluded_role),
+            skip_bots: self.skip_bots,
+            min_member_days: non_negative_option(self.min_member_days)?,
+        };
+
+        let response = match &self.role_tag {
+            Some(role_tag) => {
+                let update = RuleUpdate {
+                    eligibility,
+                    ..Default::default()
+                };
+                let role_rule =
+                    rules_handler::update_role_rule(rules, guild_id, role_tag.id.get(), update)
+                        .await?;
+                rule_to_interaction_response_data(role_rule)
+            }
+            None => {
+                let eligibility =
+                    rules_handler::update_guild_eligibility(rules, guild_id, eligibility).await?;
+                let lines = eligibility.describe();
+                let description = match lines.is_empty() {
+                    true => "Every member is eligible".to_string(),
+                    false => lines.join("\n"),
+                };
+                let embed = EmbedBuilder::new()
+                    .color(0x2f3136) // Dark theme color, render a "transparent" background
+                    .title("Guild Eligibility")
+                    .description(description)
+                    .build();
+                InteractionResponseDataBuilder::new()
+                    .embeds([embed])
+                    .build()
+            }
+        };
+
+        tokio::spawn(rules_handler::save_current_db_to_file(rules.clone()));
+
+        Ok(Some(response))
+    }
+}
+
+#[derive(CommandModel, CreateCommand, Debug)]
+#[command(
+    name = "schedule",
+    desc = "Limit a role rule to weekly windows, or set the guild's time zone"
+)]
+pub struct ScheduleRoleRule {
+    #[command(desc = "Role Tag")]
+    pub role_tag: Option<Role>,
+
+    #[command(desc = "Weekly windows, e.g. `fri 18:00-23:30; sat 20:00-02:00`, `always` clears")]
+    pub windows: Option<String>,
+
+    #[command(desc = "Guild time zone for all schedules, e.g. `Europe/London`, defaults to UTC")]
+    pub time_zone: Option<String>,
+}
+
+/// `always` clears the schedule, so the rule applies at any time
+fn parse_schedule(option: &Option<String>) -> Result<Option<BTreeSet<ScheduleWindow>>> {
+    let Some(windows) = option else {
+        return Ok(None);
+    };
+    if windows.trim().eq_ignore_ascii_case("always") {
+        return Ok(Some(BTreeSet::new()));
+    }
+
+    split_option_list(option)
+        .iter()
+        .map(|window| {
+            ScheduleWindow::from_str(window)
+                .ok_or(anyhow::anyhow!("Invalid schedule window: {}", window))
+        })
+        .collect::<Result<_>>()
+        .map(Some)
+}
+
+fn parse_time_zone(option: &Option<String>) -> Result<Option<Tz>> {
+    option
+        .as_ref()
+        .map(|time_zone| {
+            time_zone
+                .trim()
+                .parse()
+                .map_err(|_| anyhow::anyhow!("Unknown time zone: {}", time_zone))
+        })
+        .transpose()
+}
+
+impl ScheduleRoleRule {
+    pub async fn run(
+        &self,
+        interaction: &Interaction,
+        rules: &Arc<RwLock<BTreeMap<u64, GuildRules>>>,
+    ) -> Result<Option<InteractionResponseData>> {
+        let guild_id = interaction
+            .guild_id
+            .ok_or(anyhow::anyhow!("No guild id"))?
+            .get();
+        // Everything is checked before anything changes, so a bad option changes nothing
+        let schedule = parse_schedule(&self.windows)?;
+        let time_zone = parse_time_zone(&self.time_zone)?;
+        if self.role_tag.is_none() && schedule.is_some() {
+            anyhow::bail!("Schedule windows need a role tag");
+        }
+
+        let role_rule = match &self.role_tag {
+            Some(role_tag) => {
+                let update = RuleUpdate {
+                    schedule,
+                    ..Default::default()
+                };
+                Some(
+                    rules_handler::update_role_rule(rules, guild_id, role_tag.id.get(), update)
+                        .await?,
+                )
+            }
+            None => None,
+        };
+        if let Some(time_zone) = time_zone {
+            rules_handler::set_guild_time_zone(rules, guild_id, time_zone).await;
+        }
+
+        let response = match role_rule {
+            Some(role_rule) => rule_to_interaction_response_data(role_rule),
+            None => {
+                let time_zone = rules
+                    .read()
+                    .await
+                    .get(&guild_id)
+                    .map(|guild_rules| guild_rules.settings().time_zone.clone())
+                    .filter(|time_zone| !time_zone.is_empty())
+                    .unwrap_or("UTC".to_string());
+                InteractionResponseDataBuilder::new()
+                    .content(format!("Schedules are in {}", time_zone))
+                    .build()
+            }
+        };
+
+        tokio::spawn(rules_handler::save_current_db_to_file(rules.clone()));
+
+        Ok(Some(response))
+    }
+}
+
+#[derive(CommandModel, CreateCommand, Debug)]
+#[command(
+    name = "alias",
+    desc = "Map presence names to canonical activity names rules can be written against"
+)]
+pub enum AliasCommand {
+    #[command(name = "add")]
+    Add(AddAlias),
+
+    #[command(name = "remove")]
+    Remove(RemoveAlias),
+
+    #[command(name = "list")]
+    List(ListAliases),
+}
+
+#[derive(CommandModel, CreateCommand, Debug)]
+#[command(name = "add", desc = "Add aliases for a canonical activity name")]
+pub struct AddAlias {
+    #[command(desc = "Canonical name, e.g. `Quake Champions`")]
+    pub canonical: String,
+
+    #[command(
+        desc = "Presence names standing for it, `;` separated, e.g. `QC; quakechampions.exe`"
+    )]
+    pub aliases: String,
+}
+
+#[derive(CommandModel, CreateCommand, Debug)]
+#[command(name = "remove", desc = "Remove aliases")]
+pub struct RemoveAlias {
+    #[command(desc = "Aliases, `;` separated")]
+    pub aliases: String,
+}
+
+#[derive(CommandModel, CreateCommand, Debug)]
+#[command(name = "list", desc = "Shows the guild's aliases")]
+pub struct ListAliases;
+
+impl AliasCommand {
+    pub async fn run(
+        &self,
+        interaction: &Interaction,
+        rules: &Arc<RwLock<BTreeMap<u64, GuildRules>>>,
+    ) -> Result<Option<InteractionResponseData>> {
+        let guild_id = interaction
+            .guild_id
+            .ok_or(anyhow::anyhow!("No guild id"))?
+            .get();
+
+        match self {
+            AliasCommand::Add(command) => {
+                let canonical = command.canonical.trim();
+                let aliases = split_option_list(&Some(command.aliases.clone()));
+                if canonical.is_empty() || aliases.is_empty() {
+                    anyhow::bail!("Aliases need a canonical name and at least one alias");
+                }
+                rules_handler::add_aliases(rules, guild_id, canonical, aliases).await;
+                tokio::spawn(rules_handler::save_current_db_to_file(rules.clone()));
+            }
+            AliasCommand::Remove(command) => {
+                let aliases = split_option_list(&Some(command.aliases.clone()));
+                rules_handler::remove_aliases(rules, guild_id, &aliases).await?;
+                tokio::spawn(rules_handler::save_current_db_to_file(rules.clone()));
+            }
+            AliasCommand::List(_) => (),
+        }
+
+        let embed_fields: Vec<EmbedField> = rules
+            .read()
+            .await
+            .get(&guild_id)
+            .map(|guild_rules| guild_rules.aliases().clone())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(canonical, aliases)| EmbedField {
+                inline: false,
+                name: canonical,
+                value: aliases.into_iter().collect::<Vec<_>>().join(", "),
+            })
+            .collect();
+
+        let mut embed = EmbedBuilder::new()
+            .color(0x2f3136) // Dark theme color, render a "transparent" background
+            .title("Guild Aliases")
+            .build();
+        if embed_fields.is_empty() {
+            embed.description = Some("No aliases".to_string());
+        }
+        embed.fields = embed_fields;
+
+        Ok(Some(
+            InteractionResponseDataBuilder::new()
+                .embeds([embed])
+                .build(),
+        ))
+    }
+}
+
+#[derive(CommandModel, CreateCommand, Debug)]
+#[command(
+    name = "normalization",
+    desc = "Ignore ™/®, accents, full-width characters and extra spaces in activity names"
+)]
+pub struct NormalizationSettings {
+    #[command(desc = "Normalize activity names before matching, on by default")]
+    pub enabled: Option<bool>,
+}
+
+impl NormalizationSettings {
+    pub async fn run(
+        &self,
+        interaction: &Interaction,
+        rules: &Arc<RwLock<BTreeMap<u64, GuildRules>>>,
+    ) -> Result<Option<InteractionResponseData>> {
+        let guild_id = interaction
+            .guild_id
+            .ok_or(anyhow::anyhow!("No guild id"))?
+            .get();
+
+        if let Some(enabled) = self.enabled {
+            rules_handler::set_raw_activity_names(rules, guild_id, !enabled).await;
+            tokio::spawn(rules_handler::save_current_db_to_file(rules.clone()));
+        }
+
+        let raw_activity_names = rules
+            .read()
+            .await
+            .get(&guild_id)
+            .is_some_and(|guild_rules| guild_rules.settings().raw_activity_names);
+        let content = match raw_activity_names {
+            true => "Activity names are only lowercased before matching",
+            false => "Activity names are normalized before matching",
+        };
+
+        Ok(Some(
+            InteractionResponseDataBuilder::new()
+                .content(content)
+                .build(),
+        ))
+    }
+}
+
+#[derive(CommandModel, CreateCommand, Debug)]
+#[command(
+    name = "fuzzy",
+    desc = "Switch a role rule to fuzzy matching, for typos and extra words in activity names"
+)]
+pub struct FuzzyRoleRule {
+    #[command(desc = "Role Tag")]
+    pub role_tag: Role,
+
+    #[command(
+        min_value = 0,
+        max_value = 100,
+        desc = "Similarity in percent an activity needs, 0 resets it to the default 80"
+    )]
+    pub threshold: Option<i64>,
+}
+
+/// 0 stands for the default threshold, `Some(None)`
+fn parse_fuzzy_threshold(option: Option<i64>) -> Result<Option<Option<u8>>> {
+    option
+        .map(|threshold| {
+            u8::try_from(threshold)
+                .ok()
+                .filter(|threshold| *threshold <= 100)
+                .map(|threshold| Some(threshold).filter(|threshold| *threshold != 0))
+                .ok_or(anyhow::anyhow!("Invalid threshold: {}", threshold))
+        })
+        .transpose()
+}
+
+impl FuzzyRoleRule {
+    pub async fn run(
+        &self,
+        interaction: &Interaction,
+        rules: &Arc<RwLock<BTreeMap<u64, GuildRules>>>,
+    ) -> Result<Option<InteractionResponseData>> {
+        let guild_id = interaction
+            .guild_id
+            .ok_or(anyhow::anyhow!("No guild id"))?
+            .get();
+
+        let update = RuleUpdate {
+            match_mode: Some(MatchMode::Fuzzy),
+            fuzzy_threshold: parse_fuzzy_threshold(self.threshold)?,
+            ..Default::default()
+        };
+        let role_rule =
+            rules_handler::update_role_rule(rules, guild_id, self.role_tag.id.get(), update)
+                .await?;
+
+        tokio::spawn(rules_handler::save_current_db_to_file(rules.clone()));
+
+        Ok(Some(rule_to_interaction_response_data(role_rule)))
+    }
+}
+
+#[derive(CommandModel, CreateCommand, Debug)]
+#[command(
+    name = "catalog",
+    desc = "Create or edit an activity catalog, rules of any guild can attach it"
+)]
+pub struct CatalogCommand {
+    #[command(desc = "Catalog name, e.g. `quake-likes`")]
+    pub name: String,
+
+    #[command(desc = "Add Activities, `;` separated")]
+    pub add_activities: Option<String>,
+
+    #[command(desc = "Remove Activities, `;` separated")]
+    pub remove_activities: Option<String>,
+
+    #[command(desc = "Delete the catalog, it must not be attached to any rule")]
+    pub delete: Option<bool>,
+}
+
+/// Catalog names are shared by every guild, so they're compared case insensitively
+fn catalog_name(name: &str) -> String {
+    name.trim().to_lowercase()
+}
+
+/// `none` detaches every catalog
+fn parse_catalogs(option: &Option<String>) -> Option<BTreeSet<String>> {
+    let catalogs = option.as_ref()?;
+    if catalogs.trim().eq_ignore_ascii_case("none") {
+        return Some(BTreeSet::new());
+    }
+
+    Some(
+        split_option_list(option)
+            .iter()
+            .map(|name| catalog_name(name))
+            .collect(),
+    )
+}
+
+impl CatalogCommand {
+    pub async fn run(
+        &self,
+        interaction: &Interaction,
+        rules: &Arc<RwLock<BTreeMap<u64, GuildRules>>>,
+    ) -> Result<Option<InteractionResponseData>> {
+        let guild_id = interaction
+            .guild_id
+            .ok_or(anyhow::anyhow!("No guild id"))?
+            .get();
+        let name = catalog_name(&self.name);
+        if name.is_empty() {
+            anyhow::bail!("Catalog name can't be empty");
+        }
+
+        if self.delete == Some(true) {
+            rules_handler::delete_catalog(rules, guild_id, &name).await?;
+            tokio::spawn(rules_handler::save_current_db_to_file(rules.clone()));
+            return Ok(Some(
+                InteractionResponseDataBuilder::new()
+                    .content(format!("Deleted catalog {}", name))
+                    .build(),
+            ));
+        }
+
+        // Without changes it's only a lookup, of any guild's catalog
+        let activities = match (&self.add_activities, &self.remove_activities) {
+            (None, None) => rules_handler::get_catalog(rules, &name).await?,
+            _ => {
+                let activities = rules_handler::update_catalog(
+                    rules,
+                    guild_id,
+                    &name,
+                    split_option_list(&self.add_activities),
+                    split_option_list(&self.remove_activities),
+                )
+                .await?;
+                tokio::spawn(rules_handler::save_current_db_to_file(rules.clone()));
+                activities
+            }
+        };
+        let (users, other_users) = rules_handler::catalog_users(rules, guild_id, &name).await;
+
+        let activities: Vec<&str> = activities.iter().map(String::as_str).collect();
+        let mut users: Vec<String> = users.iter().map(|rule| rule.role_name.clone()).collect();
+        if other_users != 0 {
+            users.push(format!("{} rules in other servers", other_users));
+        }
+        let mut embed = EmbedBuilder::new()
+            .color(0x2f3136) // Dark theme color, render a "transparent" background
+            .title(&name)
+            .build();
+        embed.fields = vec![
+            EmbedField {
+                inline: false,
+                name: "Activities".to_string(),
+                value: match activities.is_empty() {
+                    true => "No activities".to_string(),
+                    false => activities.join(", "),
+                },
+            },
+            EmbedField {
+                inline: false,
+                name: "Used by".to_string(),
+                value: match users.is_empty() {
+                    true => "No rules".to_string(),
+                    false => users.join("\n"),
+                },
+            },
+        ];
+
+        Ok(Some(
+            InteractionResponseDataBuilder::new()
+                .embeds([embed])
+                .build(),
+        ))
+    }
+}
+
+#[derive(CommandModel, CreateCommand, Debug)]
+#[command(name = "consent", desc = "Member consent, who gets tracked")]
+pub struct ConsentSettings {
+    #[command(desc = "Only track members who opted in")]
+    pub require_opt_in: Option<bool>,
+
+    #[command(desc = "Post an opt in / opt out panel to this channel")]
+    pub panel_channel: Option<Id<ChannelMarker>>,
+}
+
+impl ConsentSettings {
+    pub async fn run(
+        &self,
+        interaction: &Interaction,
+        http_client: &Client,
+        rules: &Arc<RwLock<BTreeMap<u64, GuildRules>>>,
+    ) -> Result<Option<InteractionResponseData>> {
+        let guild_id = interaction
+            .guild_id
+            .ok_or(anyhow::anyhow!("No guild id"))?
+            .get();
+
+        if let Some(require_opt_in) = self.require_opt_in {
+            rules_handler::set_require_opt_in(rules, guild_id, require_opt_in).await;
+            tokio::spawn(rules_handler::save_current_db_to_file(rules.clone()));
+        }
+        if let Some(channel_id) = self.panel_channel {
+            post_consent_panel(http_client, channel_id).await?;
+        }
+
+        let (settings, consent) = rules
+            .read()
+            .await
+            .get(&guild_id)
+            .map(|guild_rules| {
+                (
+                    guild_rules.settings().clone(),
+                    guild_rules.consent().clone(),
+                )
+            })
+            .unwrap_or_default();
+        let mut lines = vec![match settings.require_opt_in {
+            true => "Only members who opted in are tracked".to_string(),
+            false => "Members are tracked unless they opt out".to_string(),
+        }];
+        lines.push(format!("Opted out: {}", consent.opted_out.len()));
+        if settings.require_opt_in {
+            lines.push(format!("Opted in: {}", consent.opted_in.len()));
+        }
+        if let Some(channel_id) = self.panel_channel {
+            lines.push(format!("Panel posted in <#{}>", channel_id));
+        }
+
+        let embed = EmbedBuilder::new()
+            .color(0x2f3136) // Dark theme color, render a "transparent" background
+            .title("Member Consent")
+            .description(lines.join("\n"))
+            .build();
+
+        Ok(Some(
+            InteractionResponseDataBuilder::new()
+                .embeds([embed])
+                .build(),
+        ))
+    }
+}
+
+#[derive(CommandModel, CreateCommand, Debug)]
+#[command(
+    name = "activity-roles",
+    desc = "Choose whether your activity is turned into roles",
+    dm_permission = false
+)]
+pub enum ActivityRolesCommand {
+    #[command(name = "opt-out")]
+    OptOut(OptOutCommand),
+
+    #[command(name = "opt-in")]
+    OptIn(OptInCommand),
+}
+
+#[derive(CommandModel, CreateCommand, Debug)]
+#[command(name = "opt-out", desc = "Stop getting roles from your activity")]
+pub struct OptOutCommand;
+
+#[derive(CommandModel, CreateCommand, Debug)]
+#[command(name = "opt-in", desc = "Get roles from your activity")]
+pub struct OptInCommand;
+
+impl ActivityRolesCommand {
+    /// Whether the member asked to opt in
+    pub fn opt_in(data: CommandData) -> Result<bool> {
+        let command = ActivityRolesCommand::from_interaction(data.into())
+            .context("failed to parse command data")?;
+
+        Ok(matches!(command, ActivityRolesCommand::OptIn(_)))
+    }
+}
+
 #[derive(CommandModel, CreateCommand, Debug)]
 #[command(
     name = "list",
@@ -391,6 +1334,108 @@ impl ListRoleRule {
     }
 }
 
+/// Splits a `;` separated command option, skipping empty entries
+fn split_option_list(option: &Option<String>) -> BTreeSet<String> {
+    option
+        .as_deref()
+        .unwrap_or("")
+        .split(';')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+fn parse_application_ids(option: &Option<String>) -> Result<BTreeSet<u64>> {
+    split_option_list(option)
+        .iter()
+        .map(|id| {
+            id.parse()
+                .with_context(|| format!("Invalid application id: {}", id))
+        })
+        .collect()
+}
+
+/// `any` clears the statuses, so the rule applies regardless of the member's status
+fn parse_statuses(option: &Option<String>) -> Result<Option<BTreeSet<StatusKind>>> {
+    let Some(statuses) = option else {
+        return Ok(None);
+    };
+    if statuses.trim().eq_ignore_ascii_case("any") {
+        return Ok(Some(BTreeSet::new()));
+    }
+
+    split_option_list(&Some(statuses.to_lowercase()))
+        .iter()
+        .map(|status| {
+            StatusKind::from_str(status).ok_or(anyhow::anyhow!("Unknown status: {}", status))
+        })
+        .collect::<Result<_>>()
+        .map(Some)
+}
+
+/// `main` is the guild's main rule set, which is stored without a name
+fn parse_rule_set(option: &Option<String>) -> Option<String> {
+    let rule_set = option.as_ref()?.trim();
+    match rule_set.eq_ignore_ascii_case("main") {
+        true => Some("".to_string()),
+        false => Some(rule_set.to_string()),
+    }
+}
+
+fn non_negative_option(option: Option<i64>) -> Result<Option<u64>> {
+    option
+        .map(|number| u64::try_from(number).with_context(|| format!("Invalid number: {}", number)))
+        .transpose()
+}
+
+/// `never` clears the expiry, times without an offset are in the guild's time zone
+async fn parse_expiry(
+    rules: &Arc<RwLock<BTreeMap<u64, GuildRules>>>,
+    guild_id: u64,
+    option: &Option<String>,
+) -> Result<Option<Option<DateTime<Utc>>>> {
+    let Some(expires_at) = option else {
+        return Ok(None);
+    };
+    if expires_at.trim().eq_ignore_ascii_case("never") {
+        return Ok(Some(None));
+    }
+
+    let settings = rules
+        .read()
+        .await
+        .get(&guild_id)
+        .map(|guild_rules| guild_rules.settings().clone())
+        .unwrap_or_default();
+    let expires_at = settings
+        .parse_time(expires_at)
+        .ok_or(anyhow::anyhow!("Invalid expiry time: {}", expires_at))?;
+    if expires_at <= Utc::now() {
+        anyhow::bail!("Expiry time is in the past");
+    }
+
+    Ok(Some(Some(expires_at)))
+}
+
+/// `any` clears the platforms, so the rule applies on every client
+fn parse_platforms(option: &Option<String>) -> Result<Option<BTreeSet<ClientPlatform>>> {
+    let Some(platforms) = option else {
+        return Ok(None);
+    };
+    if platforms.trim().eq_ignore_ascii_case("any") {
+        return Ok(Some(BTreeSet::new()));
+    }
+
+    split_option_list(&Some(platforms.to_lowercase()))
+        .iter()
+        .map(|platform| {
+            ClientPlatform::from_str(platform)
+                .ok_or(anyhow::anyhow!("Unknown platform: {}", platform))
+        })
+        .collect::<Result<_>>()
+        .map(Some)
+}
+
 pub fn rule_to_interaction_response_data(rule: Rule) -> InteractionResponseData {
     let mut embed = EmbedBuilder::new()
         .color(0x2f3136) // Dark theme color, render a "transparent" background
@@ -403,3 +1448,132 @@ pub fn rule_to_interaction_response_data(rule: Rule) -> InteractionResponseData
         .embeds([embed])
         .build()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn option(s: &str) -> Option<String> {
+        Some(s.to_string())
+    }
+
+    #[test]
+    fn test_parse_schedule() {
+        assert_eq!(parse_schedule(&None).unwrap(), None);
+        assert_eq!(
+            parse_schedule(&option(" Always ")).unwrap(),
+            Some(BTreeSet::new())
+        );
+
+        let windows = parse_schedule(&option("fri 18:00-23:00; sat 10:00-02:00"))
+            .unwrap()
+            .unwrap();
+        let windows: Vec<String> = windows.iter().map(|window| window.to_string()).collect();
+        assert_eq!(windows, ["fri 18:00-23:00", "sat 10:00-02:00"]);
+
+        assert!(parse_schedule(&option("fri 18:00")).is_err());
+        assert!(parse_schedule(&option("fri 25:00-26:00")).is_err());
+    }
+
+    #[test]
+    fn test_parse_time_zone() {
+        assert_eq!(parse_time_zone(&None).unwrap(), None);
+        assert_eq!(
+            parse_time_zone(&option(" Europe/London ")).unwrap(),
+            Some(Tz::Europe__London)
+        );
+        assert!(parse_time_zone(&option("Mars/Olympus")).is_err());
+    }
+
+    #[test]
+    fn test_parse_condition() {
+        assert_eq!(parse_condition(" None ").unwrap(), None);
+        assert!(
+            parse_condition("activity:tekken AND voice")
+                .unwrap()
+                .is_some()
+        );
+        assert!(parse_condition("activity:tekken AND").is_err());
+    }
+
+    #[test]
+    fn test_parse_rule_set() {
+        assert_eq!(parse_rule_set(&None), None);
+        assert_eq!(parse_rule_set(&option("Main")), Some("".to_string()));
+        assert_eq!(
+            parse_rule_set(&option(" genre ")),
+            Some("genre".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_statuses_and_platforms() {
+        assert_eq!(
+            parse_statuses(&option("any")).unwrap(),
+            Some(BTreeSet::new())
+        );
+        assert_eq!(
+            parse_statuses(&option("Online; dnd")).unwrap(),
+            Some([StatusKind::Online, StatusKind::Dnd].into())
+        );
+        assert!(parse_statuses(&option("busy")).is_err());
+
+        assert_eq!(
+            parse_platforms(&option("ANY")).unwrap(),
+            Some(BTreeSet::new())
+        );
+        assert_eq!(
+            parse_platforms(&option("mobile")).unwrap(),
+            Some([ClientPlatform::Mobile].into())
+        );
+        assert!(parse_platforms(&option("console")).is_err());
+    }
+
+    #[test]
+    fn test_parse_catalogs() {
+        assert_eq!(parse_catalogs(&None), None);
+        assert_eq!(parse_catalogs(&option("none")), Some(BTreeSet::new()));
+        assert_eq!(
+            parse_catalogs(&option("Quake-Likes; fighting")),
+            Some(["fighting".to_string(), "quake-likes".to_string()].into())
+        );
+    }
+
+    #[test]
+    fn test_numeric_options() {
+        assert_eq!(party_size_limit(Some(0)), Some(None));
+        assert_eq!(party_size_limit(Some(4)), Some(Some(4)));
+        assert_eq!(non_negative_option(Some(30)).unwrap(), Some(30));
+        assert!(non_negative_option(Some(-1)).is_err());
+        assert_eq!(parse_fuzzy_threshold(Some(90)).unwrap(), Some(Some(90)));
+        assert_eq!(parse_fuzzy_threshold(Some(0)).unwrap(), Some(None));
+        assert_eq!(parse_fuzzy_threshold(None).unwrap(), None);
+        assert!(parse_fuzzy_threshold(Some(101)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_parse_expiry() {
+        let rules = Arc::new(RwLock::new(BTreeMap::new()));
+        assert_eq!(parse_expiry(&rules, 0, &None).await.unwrap(), None);
+        assert_eq!(
+            parse_expiry(&rules, 0, &option("never")).await.unwrap(),
+            Some(None)
+        );
+        assert_eq!(
+            parse_expiry(&rules, 0, &option("2999-01-01T00:00:00Z"))
+                .await
+                .unwrap(),
+            Some(
+                DateTime::parse_from_rfc3339("2999-01-01T00:00:00Z")
+                    .ok()
+                    .map(|time| time.to_utc())
+            )
+        );
+        assert!(
+            parse_expiry(&rules, 0, &option("2000-01-01"))
+                .await
+                .is_err()
+        );
+        assert!(parse_expiry(&rules, 0, &option("tomorrow")).await.is_err());
+    }
+}
@@ -0,0 +1,66 @@
+use anyhow::Result;
+use twilight_http::Client;
+use twilight_model::{
+    channel::message::{
+        Component,
+        component::{ActionRow, Button, ButtonStyle},
+    },
+    id::{Id, marker::ChannelMarker},
+};
+
+const OPT_IN_BUTTON_ID: &str = "activity-roles-opt-in";
+const OPT_OUT_BUTTON_ID: &str = "activity-roles-opt-out";
+
+const PANEL_CONTENT: &str = "This server hands out roles based on what you're playing. \
+Opt in to let the bot see your activity, or opt out to keep it to yourself. \
+You can change your mind any time with `/activity-roles`.";
+
+fn consent_button(custom_id: &str, label: &str, style: ButtonStyle) -> Component {
+    Component::Button(Button {
+        custom_id: Some(custom_id.to_string()),
+        disabled: false,
+        emoji: None,
+        label: Some(label.to_string()),
+        style,
+        url: None,
+        sku_id: None,
+    })
+}
+
+/// Whether a consent panel button opts in, None for components that aren't the panel's
+pub fn consent_choice(custom_id: &str) -> Option<bool> {
+    match custom_id {
+        OPT_IN_BUTTON_ID => Some(true),
+        OPT_OUT_BUTTON_ID => Some(false),
+        _ => None,
+    }
+}
+
+/// The buttons' custom ids are handled by the bot for as long as the message exists
+pub async fn post_consent_panel(http_client: &Client, channel_id: Id<ChannelMarker>) -> Result<()> {
+    let components = [Component::ActionRow(ActionRow {
+        components: vec![
+            consent_button(OPT_IN_BUTTON_ID, "Opt in", ButtonStyle::Success),
+            consent_button(OPT_OUT_BUTTON_ID, "Opt out", ButtonStyle::Secondary),
+        ],
+    })];
+
+    http_client
+        .create_message(channel_id)
+        .content(PANEL_CONTENT)
+        .components(&components)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_consent_choice() {
+        assert_eq!(consent_choice(OPT_IN_BUTTON_ID), Some(true));
+        assert_eq!(consent_choice(OPT_OUT_BUTTON_ID), Some(false));
+        assert_eq!(consent_choice("something-else"), None);
+    }
+}
@@ -1,3 +1,4 @@
+mod condition;
 mod config_handler;
 mod discord_utils;
 mod event_handler;
@@ -9,7 +10,8 @@ mod rules_handler;
 use crate::{
     config_handler::EnvConfig,
     event_handler::{Bot, SHUTDOWN},
-    interactions::command::{ManageCommand, StorageCommand},
+    events::watch_rule_schedules,
+    interactions::command::{ActivityRolesCommand, ManageCommand, StorageCommand},
 };
 use anyhow::Result;
 use event_handler::runner;
@@ -46,7 +48,10 @@ async fn boot_shards(config: &EnvConfig) -> Result<(Client, Vec<Shard>)> {
     let token = config.discord_token.clone();
     // Initialize the tracing subscriber.
 
-    let intents = Intents::GUILD_PRESENCES | Intents::GUILDS | Intents::GUILD_MEMBERS;
+    let intents = Intents::GUILD_PRESENCES
+        | Intents::GUILDS
+        | Intents::GUILD_MEMBERS
+        | Intents::GUILD_VOICE_STATES;
     let client = Client::new(token.clone());
     let config = ConfigBuilder::new(token, intents)
         .presence(bot_presence("Rolling Roles".into()))
@@ -88,12 +93,16 @@ async fn main() -> Result<()> {
             &[
                 ManageCommand::create_command().into(),
                 StorageCommand::create_command().into(),
+                ActivityRolesCommand::create_command().into(),
             ],
         )
         .await?;
 
     interaction_client
-        .set_global_commands(&[ManageCommand::create_command().into()])
+        .set_global_commands(&[
+            ManageCommand::create_command().into(),
+            ActivityRolesCommand::create_command().into(),
+        ])
         .await?;
 
     let mut senders = Vec::with_capacity(shards.len());
@@ -101,6 +110,13 @@ async fn main() -> Result<()> {
 
     tracing::debug!("Spawned Shards: {}", &shards.len());
     let bot = Arc::new(Bot::new(Arc::new(client), config.github_config).await);
+    tokio::spawn(watch_rule_schedules(
+        bot.http_client.clone(),
+        bot.rules.clone(),
+        bot.cache.clone(),
+        bot.presence_update_tasks.clone(),
+        bot.member_histories.clone(),
+    ));
 
     for shard in shards {
         senders.push(shard.sender());
@@ -1,21 +1,37 @@
 use crate::{
+    condition::{Condition, ConditionContext},
     config_handler::GithubConfig,
     github_handler::{get_bytes_from_github, upload_bytes_to_github},
 };
-use anyhow::Result;
+use anyhow::{Context, Result};
 use bytes::Bytes;
+use chrono::{DateTime, Datelike, NaiveDate, NaiveDateTime, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
+use regex::{Regex, RegexBuilder};
 use serde::{Deserialize, Serialize};
 use std::{
+    borrow::Cow,
     collections::{BTreeMap, BTreeSet},
     error::Error,
-    fmt::Display,
+    fmt::{self, Display},
     fs::File,
     io::{BufReader, Read},
     sync::Arc,
+    time::Duration,
 };
 use tokio::sync::RwLock;
+use twilight_cache_inmemory::InMemoryCache;
 use twilight_interactions::command::{CommandOption, CreateOption};
-use twilight_model::{channel::message::embed::EmbedField, guild::Role};
+use twilight_model::{
+    channel::message::embed::EmbedField,
+    gateway::presence::{Activity, ActivityType, ClientStatus, Status},
+    guild::Role,
+    id::{
+        Id,
+        marker::{GuildMarker, UserMarker},
+    },
+};
+use unicode_normalization::{UnicodeNormalization, char::is_combining_mark};
 
 // use std::sync::atomic::{AtomicBool, Ordering};
 
@@ -35,8 +51,20 @@ use twilight_model::{channel::message::embed::EmbedField, guild::Role};
 pub enum RoleErrors {
     RoleAlreadyExists(u64),
     DefaultRuleAlreadyExists(u64),
+    IdleRuleAlreadyExists(u64),
     NoRulesForRole(u64),
     NoRulesForGuild(u64),
+    InvalidRegex(String),
+    UnknownCatalog(String),
+    CatalogOwnedByOtherGuild(String),
+    CatalogInUse(String),
+    UnknownAlias(String),
+    InvalidCondition(String),
+    MissingStatuses(u64),
+    MissingPlatforms(u64),
+    CatalogOnRegexRule(u64),
+    ConditionOnRuleType(u64),
+    ConditionWithMinSession(u64),
 }
 
 impl Display for RoleErrors {
@@ -46,8 +74,38 @@ impl Display for RoleErrors {
             RoleErrors::DefaultRuleAlreadyExists(role_id) => {
                 write!(f, "Default rule already exists: {}", role_id)
             }
+            RoleErrors::IdleRuleAlreadyExists(role_id) => {
+                write!(f, "Online not playing rule already exists: {}", role_id)
+            }
             RoleErrors::NoRulesForRole(role_id) => write!(f, "No rules for role: {}", role_id),
             RoleErrors::NoRulesForGuild(guild_id) => write!(f, "No rules for guild: {}", guild_id),
+            RoleErrors::InvalidRegex(error) => write!(f, "Invalid regex: {}", error),
+            RoleErrors::UnknownCatalog(name) => write!(f, "Unknown catalog: {}", name),
+            RoleErrors::CatalogOwnedByOtherGuild(name) => {
+                write!(f, "Catalog belongs to another guild: {}", name)
+            }
+            RoleErrors::CatalogInUse(name) => write!(f, "Catalog is still used by rules: {}", name),
+            RoleErrors::UnknownAlias(alias) => write!(f, "Unknown alias: {}", alias),
+            RoleErrors::InvalidCondition(reason) => write!(f, "Invalid condition: {}", reason),
+            RoleErrors::MissingStatuses(role_id) => {
+                write!(f, "Status rule needs at least one status: {}", role_id)
+            }
+            RoleErrors::MissingPlatforms(role_id) => {
+                write!(f, "Platform rule needs at least one platform: {}", role_id)
+            }
+            RoleErrors::CatalogOnRegexRule(role_id) => {
+                write!(f, "Regex rules can't use catalogs: {}", role_id)
+            }
+            RoleErrors::ConditionOnRuleType(role_id) => {
+                write!(f, "Only activity rules can have a condition: {}", role_id)
+            }
+            RoleErrors::ConditionWithMinSession(role_id) => {
+                write!(
+                    f,
+                    "Rules with a condition can't have a minimum session: {}",
+                    role_id
+                )
+            }
         }
     }
 }
@@ -61,6 +119,27 @@ pub enum RoleType {
 
     #[option(name = "Otherwise Role", value = "else")]
     Else,
+
+    #[option(name = "Regex Activity Based Role", value = "regex")]
+    Regex,
+
+    #[option(name = "Live Streaming Role", value = "streaming")]
+    Streaming,
+
+    #[option(name = "Listening (Spotify) Role", value = "listening")]
+    Listening,
+
+    #[option(name = "Custom Status Role", value = "custom-status")]
+    CustomStatus,
+
+    #[option(name = "Online but not Playing Role", value = "idle")]
+    Idle,
+
+    #[option(name = "Online Status Role", value = "status")]
+    Status,
+
+    #[option(name = "Client Platform Role", value = "platform")]
+    Platform,
 }
 
 impl RoleType {
@@ -68,6 +147,13 @@ impl RoleType {
         match s {
             "named-activity" => Some(RoleType::NamedActivity),
             "else" => Some(RoleType::Else),
+            "regex" => Some(RoleType::Regex),
+            "streaming" => Some(RoleType::Streaming),
+            "listening" => Some(RoleType::Listening),
+            "custom-status" => Some(RoleType::CustomStatus),
+            "idle" => Some(RoleType::Idle),
+            "status" => Some(RoleType::Status),
+            "platform" => Some(RoleType::Platform),
             _ => None,
         }
     }
@@ -76,509 +162,4322 @@ impl RoleType {
         match self {
             RoleType::NamedActivity => "named-activity",
             RoleType::Else => "else",
+            RoleType::Regex => "regex",
+            RoleType::Streaming => "streaming",
+            RoleType::Listening => "listening",
+            RoleType::CustomStatus => "custom-status",
+            RoleType::Idle => "idle",
+            RoleType::Status => "status",
+            RoleType::Platform => "platform",
         }
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
-pub struct Rule {
-    pub guild_id: u64,
-    pub guild_name: String,
-    pub role_id: u64,
-    pub role_name: String,
-    pub role_type: RoleType,
-    pub activities: BTreeSet<String>,
-    pub comments: String,
+#[derive(
+    Debug, Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord, CommandOption, CreateOption,
+)]
+pub enum MatchMode {
+    #[default]
+    #[option(name = "Substring", value = "substring")]
+    Substring,
+
+    #[option(name = "Whole Word", value = "whole-word")]
+    WholeWord,
+
+    #[option(name = "Exact", value = "exact")]
+    Exact,
+
+    #[option(name = "Prefix", value = "prefix")]
+    Prefix,
+
+    #[option(name = "Fuzzy", value = "fuzzy")]
+    Fuzzy,
 }
 
-impl From<Rule> for EmbedField {
-    fn from(val: Rule) -> Self {
-        let activities: Vec<String> = val.activities.iter().map(|x| x.to_string()).collect();
-        let rule_value = match val.role_type {
-            RoleType::NamedActivity => activities.join(", "),
-            RoleType::Else => "Default Role".to_string(),
-        };
+/// Similarity, in percent, a fuzzy rule needs when it doesn't set its own threshold
+pub const DEFAULT_FUZZY_THRESHOLD: u8 = 80;
 
-        EmbedField {
-            inline: false,
-            name: val.role_name,
-            value: rule_value,
+impl MatchMode {
+    fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "" | "substring" => Some(MatchMode::Substring),
+            "whole-word" => Some(MatchMode::WholeWord),
+            "exact" => Some(MatchMode::Exact),
+            "prefix" => Some(MatchMode::Prefix),
+            "fuzzy" => Some(MatchMode::Fuzzy),
+            _ => None,
+        }
+    }
+
+    fn to_str(&self) -> &str {
+        match self {
+            MatchMode::Substring => "substring",
+            MatchMode::WholeWord => "whole-word",
+            MatchMode::Exact => "exact",
+            MatchMode::Prefix => "prefix",
+            MatchMode::Fuzzy => "fuzzy",
+        }
+    }
+
+    /// Both sides are expected to be lowercased already, `fuzzy_threshold` only applies to
+    /// fuzzy matching
+    fn matches(&self, user_activity: &str, rule_activity: &str, fuzzy_threshold: u8) -> bool {
+        match self {
+            MatchMode::Fuzzy => similarity(user_activity, rule_activity) >= fuzzy_threshold,
+            MatchMode::Substring => user_activity.contains(rule_activity),
+            MatchMode::Exact => user_activity == rule_activity,
+            MatchMode::Prefix => user_activity.starts_with(rule_activity),
+            MatchMode::WholeWord => {
+                let activity_words = words(user_activity);
+                let rule_words = words(rule_activity);
+                !rule_words.is_empty()
+                    && activity_words
+                        .windows(rule_words.len())
+                        .any(|window| window == rule_words.as_slice())
+            }
         }
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
-pub struct GuildRules {
-    activities_rules: BTreeMap<u64, Rule>,
-    default_rule: Option<Rule>,
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, CommandOption, CreateOption)]
+pub enum ActivityKind {
+    #[option(name = "Playing", value = "playing")]
+    Playing,
+
+    #[option(name = "Streaming", value = "streaming")]
+    Streaming,
+
+    #[option(name = "Listening", value = "listening")]
+    Listening,
+
+    #[option(name = "Watching", value = "watching")]
+    Watching,
+
+    #[option(name = "Custom Status", value = "custom")]
+    Custom,
+
+    #[option(name = "Competing", value = "competing")]
+    Competing,
 }
 
-impl GuildRules {
-    pub fn new() -> Self {
-        GuildRules {
-            default_rule: None,
-            activities_rules: BTreeMap::new(),
+impl ActivityKind {
+    fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "playing" => Some(ActivityKind::Playing),
+            "streaming" => Some(ActivityKind::Streaming),
+            "listening" => Some(ActivityKind::Listening),
+            "watching" => Some(ActivityKind::Watching),
+            "custom" => Some(ActivityKind::Custom),
+            "competing" => Some(ActivityKind::Competing),
+            _ => None,
         }
     }
 
-    fn default_rules(&self) -> BTreeSet<Rule> {
-        match &self.default_rule {
-            None => BTreeSet::new(),
-            Some(rule) => BTreeSet::from_iter([rule.clone()]),
+    fn to_str(&self) -> &str {
+        match self {
+            ActivityKind::Playing => "playing",
+            ActivityKind::Streaming => "streaming",
+            ActivityKind::Listening => "listening",
+            ActivityKind::Watching => "watching",
+            ActivityKind::Custom => "custom",
+            ActivityKind::Competing => "competing",
         }
     }
 
-    pub fn all_rules(&self) -> BTreeSet<Rule> {
-        self.default_rules()
-            .union(&self.activities_rules.values().cloned().collect())
-            .cloned()
-            .collect()
+    fn from_activity_type(activity_type: ActivityType) -> Option<Self> {
+        match activity_type {
+            ActivityType::Playing => Some(ActivityKind::Playing),
+            ActivityType::Streaming => Some(ActivityKind::Streaming),
+            ActivityType::Listening => Some(ActivityKind::Listening),
+            ActivityType::Watching => Some(ActivityKind::Watching),
+            ActivityType::Custom => Some(ActivityKind::Custom),
+            ActivityType::Competing => Some(ActivityKind::Competing),
+            _ => None,
+        }
     }
+}
 
-    pub fn matching_rules(&self, user_activities: BTreeSet<String>) -> BTreeSet<Rule> {
-        if user_activities.is_empty() {
-            return BTreeSet::new();
-        };
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum StatusKind {
+    Online,
+    Idle,
+    Dnd,
+    Offline,
+}
 
-        let activity_rules: BTreeSet<Rule> = self
-            .activities_rules
-            .values()
-            .filter(|rule| {
-                rule.activities.iter().any(|rule_activity| {
-                    user_activities.iter().any(|user_activity| {
-                        user_activity
-                            .to_lowercase()
-                            .contains(&rule_activity.to_lowercase().to_string())
-                    })
-                })
-            })
-            .cloned()
-            .collect();
-        match activity_rules.is_empty() {
-            false => activity_rules,
-            true => self.default_rules(),
+impl StatusKind {
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "online" => Some(StatusKind::Online),
+            "idle" => Some(StatusKind::Idle),
+            "dnd" => Some(StatusKind::Dnd),
+            "offline" => Some(StatusKind::Offline),
+            _ => None,
         }
     }
 
-    pub fn get_rule(&self, role_id: u64) -> Option<&Rule> {
-        match &self.default_rule {
-            Some(rule) if rule.role_id == role_id => Some(rule),
-            _ => self.activities_rules.get(&role_id),
+    pub fn to_str(&self) -> &str {
+        match self {
+            StatusKind::Online => "online",
+            StatusKind::Idle => "idle",
+            StatusKind::Dnd => "dnd",
+            StatusKind::Offline => "offline",
         }
     }
+}
 
-    pub fn get_rule_mut(&mut self, role_id: u64) -> Option<&mut Rule> {
-        match &mut self.default_rule {
-            Some(rule) if rule.role_id == role_id => Some(rule),
-            _ => self.activities_rules.get_mut(&role_id),
+/// Invisible members look offline to everyone else, so they are treated as offline
+impl From<Status> for StatusKind {
+    fn from(status: Status) -> Self {
+        match status {
+            Status::Online => StatusKind::Online,
+            Status::Idle => StatusKind::Idle,
+            Status::DoNotDisturb => StatusKind::Dnd,
+            Status::Invisible | Status::Offline => StatusKind::Offline,
         }
     }
+}
 
-    pub fn add_rule(&mut self, rule: Rule) -> Result<()> {
-        match rule.role_type {
-            RoleType::NamedActivity => match self.activities_rules.contains_key(&rule.role_id) {
-                true => Err(RoleErrors::RoleAlreadyExists(rule.role_id).into()),
-                false => {
-                    self.activities_rules.insert(rule.role_id, rule);
-                    Ok(())
-                }
-            },
-            RoleType::Else => match &self.default_rule {
-                Some(_) => Err(RoleErrors::DefaultRuleAlreadyExists(rule.role_id).into()),
-                None => {
-                    self.default_rule = Some(rule);
-                    Ok(())
-                }
-            },
+/// The client a member is connected from, a member can be on several at once
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ClientPlatform {
+    Desktop,
+    Mobile,
+    Web,
+}
+
+impl ClientPlatform {
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "desktop" => Some(ClientPlatform::Desktop),
+            "mobile" => Some(ClientPlatform::Mobile),
+            "web" => Some(ClientPlatform::Web),
+            _ => None,
         }
     }
 
-    pub fn remove_rule(&mut self, role_id: u64) -> Result<()> {
-        if self.activities_rules.contains_key(&role_id) {
-            self.activities_rules.remove(&role_id);
-            Ok(())
-        } else if let Some(rule) = &self.default_rule
-            && rule.role_id == role_id
-        {
-            self.default_rule = None;
-            Ok(())
-        } else {
-            Err(RoleErrors::NoRulesForRole(role_id).into())
+    fn to_str(&self) -> &str {
+        match self {
+            ClientPlatform::Desktop => "desktop",
+            ClientPlatform::Mobile => "mobile",
+            ClientPlatform::Web => "web",
         }
     }
+}
 
-    #[allow(dead_code)]
-    pub fn edit_rule(&mut self, rule: Rule) -> Result<()> {
-        if let std::collections::btree_map::Entry::Occupied(mut e) = self.activities_rules.entry(rule.role_id) {
-            e.insert(rule);
-            Ok(())
-        } else {
-            Err(RoleErrors::NoRulesForRole(rule.role_id).into())
+/// What the bot knows about a member's presence when deciding on their roles
+#[derive(Debug, Clone)]
+pub struct UserPresence {
+    pub activities: Vec<Activity>,
+    pub status: Status,
+    pub platforms: BTreeSet<ClientPlatform>,
+}
+
+impl UserPresence {
+    pub fn new<'a, T: Iterator<Item = &'a Activity>>(
+        activities: T,
+        status: Status,
+        client_status: &ClientStatus,
+    ) -> Self {
+        Self {
+            activities: user_activities_from_presence(activities),
+            status,
+            platforms: platforms_from_client_status(client_status),
+        }
+    }
+
+    pub fn offline() -> Self {
+        Self {
+            activities: Vec::new(),
+            status: Status::Offline,
+            platforms: BTreeSet::new(),
         }
     }
 }
 
-impl From<GuildRules> for Vec<EmbedField> {
-    fn from(val: GuildRules) -> Self {
-        val.activities_rules
-            .values()
-            .map(|r| r.clone().into())
-            .chain(val.default_rule.iter().map(|r| r.clone().into()))
-            .collect()
+/// Discord only lists the clients a member is connected from
+fn platforms_from_client_status(client_status: &ClientStatus) -> BTreeSet<ClientPlatform> {
+    [
+        (client_status.desktop, ClientPlatform::Desktop),
+        (client_status.mobile, ClientPlatform::Mobile),
+        (client_status.web, ClientPlatform::Web),
+    ]
+    .into_iter()
+    .filter(|(status, _)| status.is_some_and(|status| status != Status::Offline))
+    .map(|(_, platform)| platform)
+    .collect()
+}
+
+/// What rule eligibility needs to know about a member
+#[derive(Debug, Clone, Default)]
+pub struct MemberInfo {
+    pub user_id: u64,
+    pub roles: BTreeSet<u64>,
+    pub is_bot: bool,
+    /// Milliseconds since the unix epoch
+    pub joined_at: Option<u64>,
+    pub in_voice: bool,
+}
+
+impl MemberInfo {
+    pub fn from_cache(
+        cache: &InMemoryCache,
+        guild_id: Id<GuildMarker>,
+        user_id: Id<UserMarker>,
+    ) -> Option<Self> {
+        let member = cache.member(guild_id, user_id)?;
+        Some(Self {
+            user_id: user_id.get(),
+            roles: member.roles().iter().map(|role_id| role_id.get()).collect(),
+            is_bot: cache.user(user_id).is_some_and(|user| user.bot),
+            joined_at: member
+                .joined_at()
+                .map(|joined_at| (joined_at.as_micros() / 1000) as u64),
+            in_voice: cache.voice_state(user_id, guild_id).is_some(),
+        })
     }
 }
 
-impl From<CsvRow> for Rule {
-    fn from(row: CsvRow) -> Self {
-        let guild_id = row.guild_id.parse().expect("Invalid guild_id");
+/// Collects every kind of activity, rules decide which activity types count
+fn user_activities_from_presence<'a, T: Iterator<Item = &'a Activity>>(
+    activities: T,
+) -> Vec<Activity> {
+    activities.cloned().collect()
+}
 
-        let role_type = RoleType::from_str(&row.role_type)
-            .unwrap_or_else(|| panic!("Unknown role_type: {}", row.role_type));
+/// Which part of a Listening activity the rule's keywords are matched against
+#[derive(
+    Debug, Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord, CommandOption, CreateOption,
+)]
+pub enum ListeningField {
+    #[default]
+    #[option(name = "Artist or Track", value = "any")]
+    Any,
 
-        let activities = row
-            .activity_names
-            .split(';')
-            .map(|s| s.trim().to_string())
-            .filter(|s| !s.is_empty())
-            .collect();
+    #[option(name = "Artist", value = "artist")]
+    Artist,
 
-        Rule {
-            guild_id,
-            guild_name: row.guild_name,
-            role_id: row.role_id.parse().expect("Invalid role_id"),
-            role_name: row.role_name,
-            role_type,
-            activities,
-            comments: row.comments,
+    #[option(name = "Track", value = "track")]
+    Track,
+}
+
+impl ListeningField {
+    fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "" | "any" => Some(ListeningField::Any),
+            "artist" => Some(ListeningField::Artist),
+            "track" => Some(ListeningField::Track),
+            _ => None,
         }
     }
-}
 
-impl From<Rule> for CsvRow {
-    fn from(val: Rule) -> Self {
-        let mut activities: Vec<String> = val.activities.iter().cloned().collect();
-        activities.sort();
-        CsvRow {
-            guild_id: val.guild_id.to_string(),
-            guild_name: val.guild_name,
-            role_id: val.role_id.to_string(),
-            role_name: val.role_name,
-            role_type: val.role_type.to_str().to_string(),
-            activity_names: activities.join(";"),
-            comments: val.comments,
+    fn to_str(&self) -> &str {
+        match self {
+            ListeningField::Any => "any",
+            ListeningField::Artist => "artist",
+            ListeningField::Track => "track",
         }
     }
-}
 
-impl From<GuildRules> for Vec<CsvRow> {
-    fn from(val: GuildRules) -> Self {
-        let mut rows = match val.default_rule {
-            Some(r) => vec![r.into()],
-            None => vec![],
-        };
-        rows.extend(val.activities_rules.values().map(|r| r.clone().into()));
-        rows
+    /// Spotify puts the artists in `state` and the track in `details`
+    fn fields<'a>(&self, user_activity: &'a Activity) -> Vec<&'a str> {
+        let artist = user_activity.state.as_deref();
+        let track = user_activity.details.as_deref();
+        match self {
+            ListeningField::Any => [artist, track].into_iter().flatten().collect(),
+            ListeningField::Artist => artist.into_iter().collect(),
+            ListeningField::Track => track.into_iter().collect(),
+        }
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct CsvRow {
-    guild_id: String,
-    guild_name: String,
-    role_id: String,
-    role_name: String,
-
-    #[serde(rename = "type")]
-    role_type: String,
-
-    activity_names: String,
-    comments: String,
+/// Folds an activity name or keyword for matching: drops ™, ® and ©, applies NFKC so full-width
+/// and other compatibility characters become plain ones, strips diacritics, lowercases and
+/// collapses whitespace
+pub fn normalize_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .filter(|c| !matches!(c, '™' | '®' | '©'))
+        .nfkc()
+        .nfd()
+        .filter(|c| !is_combining_mark(*c))
+        .nfc()
+        .collect();
+    name.to_lowercase()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
 }
 
-pub async fn update_roles_names(
-    rules: Arc<RwLock<BTreeMap<u64, GuildRules>>>,
-    guild_roles: Vec<Role>,
-    guild_id: u64,
-    guild_name: String,
-) -> Result<()> {
-    let mut wrtr = rules.write().await;
-    let guild_rules = wrtr
-        .get_mut(&guild_id)
-        .ok_or(RoleErrors::NoRulesForGuild(guild_id))?;
+fn fold_name(name: &str, raw_activity_names: bool) -> String {
+    match raw_activity_names {
+        true => name.to_lowercase(),
+        false => normalize_name(name),
+    }
+}
 
-    guild_roles.iter().for_each(|guild_role| {
-        let role_id = guild_role.id.into();
+fn words(s: &str) -> Vec<&str> {
+    s.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect()
+}
 
-        // update rule's role name
-        let rule = guild_rules.get_rule_mut(role_id);
-        if let Some(rule) = rule {
-            rule.role_name = guild_role.name.to_string();
-            rule.guild_name = guild_name.clone()
+fn edit_distance(a: &[char], b: &[char]) -> usize {
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.iter().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != b_char);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
         }
-    });
-
-    Ok(())
+        previous = current;
+    }
+    previous[b.len()]
 }
 
-pub async fn update_role_rule(
-    rules: &Arc<RwLock<BTreeMap<u64, GuildRules>>>,
-    guild_id: u64,
-    role_id: u64,
-    add_activities: BTreeSet<String>,
-    remove_activities: BTreeSet<String>,
-    comments: String,
-) -> Result<Rule> {
-    let mut wrtr = rules.write().await;
-    let guild_rules = wrtr
-        .get_mut(&guild_id)
-        .ok_or(RoleErrors::NoRulesForGuild(guild_id))?;
+/// Edit distance scaled to a percentage of the longer string, 100 means equal
+fn edit_similarity(a: &str, b: &str) -> u8 {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let longest = a.len().max(b.len());
+    if longest == 0 {
+        return 100;
+    }
+    (100 - edit_distance(&a, &b) * 100 / longest) as u8
+}
 
-    let rule = guild_rules
-        .get_rule_mut(role_id)
-        .ok_or(RoleErrors::NoRulesForRole(role_id))?;
+/// The best of the whole names' similarity and the rule's words' average similarity to their
+/// closest activity word, so typos and extra words like "(Beta)" or "EU" both stay close
+fn similarity(user_activity: &str, rule_activity: &str) -> u8 {
+    let activity_words = words(user_activity);
+    let rule_words = words(rule_activity);
+    let word_similarity = match rule_words.is_empty() {
+        true => 0,
+        false => {
+            let total: usize = rule_words
+                .iter()
+                .map(|rule_word| {
+                    activity_words
+                        .iter()
+                        .map(|word| edit_similarity(word, rule_word))
+                        .max()
+                        .unwrap_or(0) as usize
+                })
+                .sum();
+            (total / rule_words.len()) as u8
+        }
+    };
+    edit_similarity(user_activity, rule_activity).max(word_similarity)
+}
 
-    let new_activities = rule
-        .activities
-        .union(&add_activities)
-        .filter(|activity| !remove_activities.contains(*activity))
-        .cloned()
-        .collect();
-    rule.activities = new_activities;
-    rule.comments = comments;
+/// Optional conditions on an activity's rich presence, every non empty condition has to hold
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RichPresenceMatcher {
+    pub details: BTreeSet<String>,
+    pub state: BTreeSet<String>,
+    pub large_text: BTreeSet<String>,
+    pub min_party_size: Option<u64>,
+    pub max_party_size: Option<u64>,
+}
 
-    Ok(rule.clone())
+/// `keywords` are folded already, the field is folded the same way
+fn field_contains_any(field: Option<&str>, keywords: &[String], raw_activity_names: bool) -> bool {
+    if keywords.is_empty() {
+        return true;
+    }
+    let Some(field) = field else {
+        return false;
+    };
+    let field = fold_name(field, raw_activity_names);
+    keywords.iter().any(|keyword| field.contains(keyword))
 }
 
-pub fn load_rules_from_buffer<R: Read>(reader: R) -> Result<BTreeMap<u64, GuildRules>> {
-    let mut reader_buffer = csv::Reader::from_reader(reader);
-    let mut rules = BTreeMap::new();
+impl RichPresenceMatcher {
+    /// The keywords are matched in their folded form, from the rule's compiled keywords
+    fn matches(
+        &self,
+        user_activity: &Activity,
+        compiled: &CompiledKeywords,
+        raw_activity_names: bool,
+    ) -> bool {
+        let large_text = user_activity
+            .assets
+            .as_ref()
+            .and_then(|assets| assets.large_text.as_deref());
+        let party_size = user_activity
+            .party
+            .as_ref()
+            .and_then(|party| party.size)
+            .map(|[current, _max]| current);
 
-    for result in reader_buffer.deserialize() {
-        let row: CsvRow = result?;
-        let rule: Rule = row.into();
+        let party_size_matches = match (self.min_party_size, self.max_party_size) {
+            (None, None) => true,
+            (min, max) => party_size.is_some_and(|size| {
+                min.is_none_or(|min| size >= min) && max.is_none_or(|max| size <= max)
+            }),
+        };
 
-        let guild_rules = rules.entry(rule.guild_id).or_insert(GuildRules::new());
+        field_contains_any(
+            user_activity.details.as_deref(),
+            compiled.details.get(raw_activity_names),
+            raw_activity_names,
+        ) && field_contains_any(
+            user_activity.state.as_deref(),
+            compiled.state.get(raw_activity_names),
+            raw_activity_names,
+        ) && field_contains_any(
+            large_text,
+            compiled.large_text.get(raw_activity_names),
+            raw_activity_names,
+        ) && party_size_matches
+    }
 
-        match rule.role_type {
-            RoleType::NamedActivity => {
-                guild_rules.activities_rules.insert(rule.role_id, rule);
-            }
-            RoleType::Else => {
-                guild_rules.default_rule = Some(rule);
+    fn describe(&self) -> Vec<String> {
+        let mut lines = vec![];
+        for (name, keywords) in [
+            ("Details", &self.details),
+            ("State", &self.state),
+            ("Large Text", &self.large_text),
+        ] {
+            if !keywords.is_empty() {
+                let keywords: Vec<String> = keywords.iter().cloned().collect();
+                lines.push(format!("{}: {}", name, keywords.join(", ")));
             }
         }
+        match (self.min_party_size, self.max_party_size) {
+            (None, None) => (),
+            (min, max) => lines.push(format!(
+                "Party Size: {}-{}",
+                min.map(|min| min.to_string()).unwrap_or_default(),
+                max.map(|max| max.to_string()).unwrap_or_default()
+            )),
+        }
+        lines
     }
-
-    Ok(rules)
 }
 
-pub fn load_rules_from_file(file_path: String) -> Result<BTreeMap<u64, GuildRules>> {
-    let file = File::open(file_path)?;
-    load_rules_from_buffer(BufReader::new(file))
-}
+const MINUTES_PER_DAY: u32 = 24 * 60;
+const MINUTES_PER_WEEK: u32 = 7 * MINUTES_PER_DAY;
+const WEEKDAYS: [&str; 7] = [
+    "monday",
+    "tuesday",
+    "wednesday",
+    "thursday",
+    "friday",
+    "saturday",
+    "sunday",
+];
 
-pub fn load_db_from_file() -> Result<BTreeMap<u64, GuildRules>> {
-    load_rules_from_file("db/db.csv".to_string())
+/// A weekly window in the guild's time zone, e.g. `fri 18:00-23:30`.
+/// Windows that end at or before their start run past midnight
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ScheduleWindow {
+    /// Days since monday
+    weekday: u32,
+    /// Minutes since midnight
+    start: u32,
+    end: u32,
 }
 
-pub async fn load_rules_from_github(
-    github_config: &GithubConfig,
-) -> Result<BTreeMap<u64, GuildRules>> {
-    load_rules_from_buffer(
-        get_bytes_from_github(
-            &github_config.owner,
-            &github_config.repo,
-            &github_config.path,
-            &github_config.branch,
+fn parse_time_of_day(s: &str) -> Option<u32> {
+    let (hours, minutes) = s.trim().split_once(':')?;
+    let (hours, minutes): (u32, u32) = (hours.parse().ok()?, minutes.parse().ok()?);
+    match (hours, minutes) {
+        (24, 0) => Some(0),
+        (0..24, 0..60) => Some(hours * 60 + minutes),
+        _ => None,
+    }
+}
+
+impl ScheduleWindow {
+    pub fn from_str(s: &str) -> Option<Self> {
+        let (day, times) = s.trim().split_once(' ')?;
+        // A day is its full name or the start of it, `fri` or `friday`
+        let day = day.to_lowercase();
+        if day.len() < 3 {
+            return None;
+        }
+        let weekday = WEEKDAYS.iter().position(|name| name.starts_with(&day))? as u32;
+        let (start, end) = times.split_once('-')?;
+
+        Some(ScheduleWindow {
+            weekday,
+            start: parse_time_of_day(start)?,
+            end: parse_time_of_day(end)?,
+        })
+    }
+
+    /// `minute_of_week` counts from monday midnight
+    pub fn contains(&self, minute_of_week: u32) -> bool {
+        let start = self.weekday * MINUTES_PER_DAY + self.start;
+        let length = (self.end + MINUTES_PER_DAY - self.start - 1) % MINUTES_PER_DAY + 1;
+        (minute_of_week + MINUTES_PER_WEEK - start) % MINUTES_PER_WEEK < length
+    }
+}
+
+impl Display for ScheduleWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {:02}:{:02}-{:02}:{:02}",
+            &WEEKDAYS[self.weekday as usize][..3],
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
         )
-        .await?
-        .as_slice(),
-    )
+    }
+}
+
+/// Which members rules apply to, set for the whole guild and per rule
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Eligibility {
+    /// Members need at least one of these roles, empty means no requirement
+    pub required_roles: BTreeSet<u64>,
+    pub excluded_roles: BTreeSet<u64>,
+    pub skip_bots: bool,
+    /// Days since the member joined the guild
+    pub min_member_days: u64,
+}
+
+impl Eligibility {
+    pub fn allows(&self, member: &MemberInfo, now: u64) -> bool {
+        let joined_long_enough = self.min_member_days == 0
+            || member.joined_at.is_some_and(|joined_at| {
+                now.saturating_sub(joined_at)
+                    >= self.min_member_days.saturating_mul(24 * 60 * 60 * 1000)
+            });
+
+        (self.required_roles.is_empty() || !self.required_roles.is_disjoint(&member.roles))
+            && self.excluded_roles.is_disjoint(&member.roles)
+            && !(self.skip_bots && member.is_bot)
+            && joined_long_enough
+    }
+
+    pub fn describe(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        for (name, roles) in [
+            ("Requires", &self.required_roles),
+            ("Not for", &self.excluded_roles),
+        ] {
+            if !roles.is_empty() {
+                let roles: Vec<String> = roles.iter().map(|id| format!("<@&{}>", id)).collect();
+                lines.push(format!("{}: {}", name, roles.join(", ")));
+            }
+        }
+        if self.skip_bots {
+            lines.push("Skipping bots".to_string());
+        }
+        if self.min_member_days != 0 {
+            lines.push(format!(
+                "Members for at least {} days",
+                self.min_member_days
+            ));
+        }
+        lines
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Rule {
+    pub guild_id: u64,
+    pub guild_name: String,
+    pub role_id: u64,
+    pub role_name: String,
+    pub role_type: RoleType,
+    pub activities: BTreeSet<String>,
+    /// Shared activity catalogs the rule matches on top of its own activities
+    pub catalogs: BTreeSet<String>,
+    /// The catalogs' activities, resolved from every guild's catalogs rather than stored
+    pub catalog_activities: BTreeSet<String>,
+    pub match_mode: MatchMode,
+    /// Similarity in percent a fuzzy match needs, the default threshold when unset
+    pub fuzzy_threshold: Option<u8>,
+    pub exclusions: BTreeSet<String>,
+    pub application_ids: BTreeSet<u64>,
+    /// Activity types the rule applies to, empty means Playing only
+    pub activity_types: BTreeSet<ActivityKind>,
+    /// Ignore activities that don't come from a Discord application, i.e. custom statuses
+    pub verified_only: bool,
+    pub rich_presence: RichPresenceMatcher,
+    /// Within an exclusive group only the matching rule with the highest priority is assigned
+    pub priority: i64,
+    pub exclusive_group: Option<String>,
+    /// Rules are evaluated per rule set, the unnamed set is the guild's main one
+    pub rule_set: String,
+    pub listening_field: ListeningField,
+    pub eligibility: Eligibility,
+    /// The rule only applies while the member's status is one of these, empty means any status.
+    /// Status rules are granted by the status alone
+    pub statuses: BTreeSet<StatusKind>,
+    /// The rule only applies while the member is on one of these clients, empty means any client.
+    /// Platform rules are granted by the client alone
+    pub platforms: BTreeSet<ClientPlatform>,
+    /// The rule only applies within these weekly windows, empty means always
+    pub schedule: BTreeSet<ScheduleWindow>,
+    /// The role is only granted once the activity has been going on for this long
+    pub min_session_secs: u64,
+    /// Removing the role is delayed by this long, so a restarted game or a dropped activity
+    /// doesn't flap it
+    pub linger_secs: u64,
+    /// Streaming rules post a go-live embed to this channel when the role is granted
+    pub announce_channel_id: Option<u64>,
+    /// Expired rules stop matching and are archived, their role is removed from everyone
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Replaces matching the activities, the rule is granted while the condition holds
+    pub condition: Option<Condition>,
+    pub comments: String,
+    /// Set by `compile`
+    pub compiled: CompiledKeywords,
+}
+
+/// What a rule's keywords are matched with, built once when the rule is loaded or edited rather
+/// than on every presence update. It only derives from the rule's other fields, so comparing or
+/// hashing rules leaves it out
+#[derive(Debug, Clone, Default)]
+pub struct CompiledKeywords {
+    regexes: Vec<Regex>,
+    /// The rule's activities and its catalogs' activities
+    keywords: FoldedNames,
+    exclusions: FoldedNames,
+    details: FoldedNames,
+    state: FoldedNames,
+    large_text: FoldedNames,
+}
+
+/// Names folded both ways the guild's settings can have activity names compared
+#[derive(Debug, Clone, Default)]
+struct FoldedNames {
+    normalized: Vec<String>,
+    lowercased: Vec<String>,
+}
+
+impl FoldedNames {
+    fn new<'a>(names: impl IntoIterator<Item = &'a String>) -> Self {
+        let (normalized, lowercased) = names
+            .into_iter()
+            .map(|name| (normalize_name(name), name.to_lowercase()))
+            .unzip();
+        FoldedNames {
+            normalized,
+            lowercased,
+        }
+    }
+
+    fn get(&self, raw_activity_names: bool) -> &[String] {
+        match raw_activity_names {
+            true => &self.lowercased,
+            false => &self.normalized,
+        }
+    }
+}
+
+impl PartialEq for CompiledKeywords {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for CompiledKeywords {}
+
+impl PartialOrd for CompiledKeywords {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for CompiledKeywords {
+    fn cmp(&self, _other: &Self) -> std::cmp::Ordering {
+        std::cmp::Ordering::Equal
+    }
+}
+
+impl std::hash::Hash for CompiledKeywords {
+    fn hash<H: std::hash::Hasher>(&self, _state: &mut H) {}
+}
+
+/// Milliseconds since the unix epoch, the unit Discord uses for activity timestamps
+fn epoch_millis(time: DateTime<Utc>) -> u64 {
+    u64::try_from(time.timestamp_millis()).unwrap_or_default()
+}
+
+/// Human friendly durations for the rule embeds, e.g. `5m 30s`
+fn format_duration(secs: u64) -> String {
+    match (secs / 3600, secs % 3600 / 60, secs % 60) {
+        (0, 0, seconds) => format!("{}s", seconds),
+        (0, minutes, 0) => format!("{}m", minutes),
+        (0, minutes, seconds) => format!("{}m {}s", minutes, seconds),
+        (hours, 0, 0) => format!("{}h", hours),
+        (hours, minutes, _) => format!("{}h {}m", hours, minutes),
+    }
+}
+
+/// Regex rules are matched case insensitively, same as named activities
+fn build_regex(pattern: &str) -> Result<Regex, RoleErrors> {
+    RegexBuilder::new(pattern)
+        .case_insensitive(true)
+        .build()
+        .map_err(|e| RoleErrors::InvalidRegex(e.to_string()))
+}
+
+impl Rule {
+    /// A rule with only its role set, everything else left at what a fresh rule starts with
+    pub fn new(
+        guild_id: u64,
+        guild_name: String,
+        role_id: u64,
+        role_name: String,
+        role_type: RoleType,
+    ) -> Self {
+        Rule {
+            guild_id,
+            guild_name,
+            role_id,
+            role_name,
+            role_type,
+            activities: BTreeSet::new(),
+            catalogs: BTreeSet::new(),
+            catalog_activities: BTreeSet::new(),
+            match_mode: MatchMode::Substring,
+            fuzzy_threshold: None,
+            exclusions: BTreeSet::new(),
+            application_ids: BTreeSet::new(),
+            activity_types: BTreeSet::new(),
+            verified_only: false,
+            rich_presence: RichPresenceMatcher::default(),
+            priority: 0,
+            exclusive_group: None,
+            rule_set: "".to_string(),
+            listening_field: ListeningField::Any,
+            eligibility: Eligibility::default(),
+            statuses: BTreeSet::new(),
+            platforms: BTreeSet::new(),
+            schedule: BTreeSet::new(),
+            min_session_secs: 0,
+            linger_secs: 0,
+            announce_channel_id: None,
+            expires_at: None,
+            condition: None,
+            comments: "".to_string(),
+            compiled: CompiledKeywords::default(),
+        }
+    }
+
+    /// Makes sure the rule can match and compiles its activities, i.e. builds a regex rule's
+    /// regexes and folds its keywords. Needed whenever the rule is loaded or edited
+    pub fn compile(&mut self) -> Result<(), RoleErrors> {
+        self.validate()?;
+        self.compiled.regexes = match self.role_type {
+            RoleType::Regex => self
+                .activities
+                .iter()
+                .map(|pattern| build_regex(pattern))
+                .collect::<Result<_, _>>()?,
+            _ => vec![],
+        };
+        self.fold_keywords();
+        Ok(())
+    }
+
+    /// Folds the keywords for matching, again whenever the catalogs' activities change
+    fn fold_keywords(&mut self) {
+        self.compiled.keywords = FoldedNames::new(self.keywords());
+        self.compiled.exclusions = FoldedNames::new(&self.exclusions);
+        self.compiled.details = FoldedNames::new(&self.rich_presence.details);
+        self.compiled.state = FoldedNames::new(&self.rich_presence.state);
+        self.compiled.large_text = FoldedNames::new(&self.rich_presence.large_text);
+    }
+
+    /// Rejects rules that could never match
+    fn validate(&self) -> Result<(), RoleErrors> {
+        if self.role_type == RoleType::Status && self.statuses.is_empty() {
+            return Err(RoleErrors::MissingStatuses(self.role_id));
+        }
+        if self.role_type == RoleType::Platform && self.platforms.is_empty() {
+            return Err(RoleErrors::MissingPlatforms(self.role_id));
+        }
+        // Catalog activities are plain names, a regex rule only matches its own patterns
+        if self.role_type == RoleType::Regex && !self.catalogs.is_empty() {
+            return Err(RoleErrors::CatalogOnRegexRule(self.role_id));
+        }
+        // A condition replaces matching the activities, default, idle, status and platform rules
+        // aren't granted by one and a condition has no activity to time a session by
+        if self.condition.is_some() {
+            if matches!(
+                self.role_type,
+                RoleType::Else | RoleType::Idle | RoleType::Status | RoleType::Platform
+            ) {
+                return Err(RoleErrors::ConditionOnRuleType(self.role_id));
+            }
+            if self.min_session_secs != 0 {
+                return Err(RoleErrors::ConditionWithMinSession(self.role_id));
+            }
+        }
+        Ok(())
+    }
+
+    /// The rule's own activities followed by the ones of its catalogs
+    fn keywords(&self) -> impl Iterator<Item = &String> {
+        self.activities.iter().chain(self.catalog_activities.iter())
+    }
+
+    /// Exclusions veto a match, even when one of the rule's activities hits
+    fn is_excluded(&self, user_activity: &str, raw_activity_names: bool) -> bool {
+        let user_activity = fold_name(user_activity, raw_activity_names);
+        self.compiled
+            .exclusions
+            .get(raw_activity_names)
+            .iter()
+            .any(|exclusion| user_activity.contains(exclusion))
+    }
+
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    pub fn is_scheduled(&self, minute_of_week: u32) -> bool {
+        self.schedule.is_empty()
+            || self
+                .schedule
+                .iter()
+                .any(|window| window.contains(minute_of_week))
+    }
+
+    pub fn allows_status(&self, status: &StatusKind) -> bool {
+        self.statuses.is_empty() || self.statuses.contains(status)
+    }
+
+    pub fn allows_platforms(&self, platforms: &BTreeSet<ClientPlatform>) -> bool {
+        self.platforms.is_empty() || !self.platforms.is_disjoint(platforms)
+    }
+
+    /// Status and Platform rules are granted by the presence itself rather than by an activity
+    pub fn matches_presence(
+        &self,
+        status: &StatusKind,
+        platforms: &BTreeSet<ClientPlatform>,
+    ) -> bool {
+        match self.role_type {
+            RoleType::Status => self.statuses.contains(status) && self.allows_platforms(platforms),
+            RoleType::Platform => {
+                !self.platforms.is_disjoint(platforms) && self.allows_status(status)
+            }
+            _ => false,
+        }
+    }
+
+    /// How much longer the activity has to go on before the rule's minimum session is met.
+    /// Activities that don't say when they started are taken at face value
+    pub fn remaining_session(&self, user_activity: &Activity, now: u64) -> Duration {
+        let started_at = user_activity
+            .timestamps
+            .as_ref()
+            .and_then(|timestamps| timestamps.start)
+            .or(user_activity.created_at);
+        let Some(started_at) = started_at else {
+            return Duration::ZERO;
+        };
+
+        let ready_at = started_at.saturating_add(self.min_session_secs.saturating_mul(1000));
+        Duration::from_millis(ready_at.saturating_sub(now))
+    }
+
+    pub fn applies_to_kind(&self, activity_type: ActivityType) -> bool {
+        match self.role_type {
+            RoleType::Streaming => return activity_type == ActivityType::Streaming,
+            RoleType::Listening => return activity_type == ActivityType::Listening,
+            RoleType::CustomStatus => return activity_type == ActivityType::Custom,
+            RoleType::Status | RoleType::Platform => return false,
+            _ => (),
+        }
+        match ActivityKind::from_activity_type(activity_type) {
+            None => false,
+            Some(ActivityKind::Playing) if self.activity_types.is_empty() => true,
+            Some(kind) => self.activity_types.contains(&kind),
+        }
+    }
+
+    /// What an activity has to pass before the rule looks at its name, whether the rule matches
+    /// on its keywords or on a condition: its type, where it comes from, the exclusions and the
+    /// rich presence
+    pub fn accepts_activity(&self, user_activity: &Activity, raw_activity_names: bool) -> bool {
+        self.applies_to_kind(user_activity.kind)
+            && (!self.verified_only || user_activity.application_id.is_some())
+            && !self.is_excluded(&user_activity.name, raw_activity_names)
+            && self
+                .rich_presence
+                .matches(user_activity, &self.compiled, raw_activity_names)
+    }
+
+    /// `raw_activity_names` is the guild's setting, names are only lowercased when it's set
+    pub fn matches_activity(&self, user_activity: &Activity, raw_activity_names: bool) -> bool {
+        if !self.accepts_activity(user_activity, raw_activity_names) {
+            return false;
+        }
+
+        let application_match = user_activity
+            .application_id
+            .is_some_and(|application_id| self.application_ids.contains(&application_id.get()));
+        let activity_match = match self.role_type {
+            RoleType::Streaming => self.matches_stream(user_activity, raw_activity_names),
+            RoleType::Listening => self
+                .listening_field
+                .fields(user_activity)
+                .into_iter()
+                .any(|field| self.matches_keywords(field, raw_activity_names)),
+            RoleType::CustomStatus => self.matches_custom_status(user_activity, raw_activity_names),
+            _ => self.matches_name(&user_activity.name, raw_activity_names),
+        };
+        application_match || activity_match
+    }
+
+    /// Any stream matches when no keywords are set, otherwise the platform, title or game has to
+    fn matches_stream(&self, user_activity: &Activity, raw_activity_names: bool) -> bool {
+        if self.keywords().next().is_none() {
+            return true;
+        }
+        [
+            Some(user_activity.name.as_str()),
+            user_activity.details.as_deref(),
+            user_activity.state.as_deref(),
+        ]
+        .into_iter()
+        .flatten()
+        .any(|field| self.matches_keywords(field, raw_activity_names))
+    }
+
+    /// Keywords are matched against the status text, the status emoji has to match exactly, by
+    /// the emoji itself, a custom emoji's name or its id
+    fn matches_custom_status(&self, user_activity: &Activity, raw_activity_names: bool) -> bool {
+        let text_match = user_activity
+            .state
+            .as_deref()
+            .is_some_and(|text| self.matches_keywords(text, raw_activity_names));
+        let emoji_match = user_activity.emoji.as_ref().is_some_and(|emoji| {
+            self.keywords().any(|keyword| {
+                keyword.eq_ignore_ascii_case(&emoji.name)
+                    || emoji.id.as_deref() == Some(keyword.as_str())
+            })
+        });
+        text_match || emoji_match
+    }
+
+    fn matches_keywords(&self, user_activity: &str, raw_activity_names: bool) -> bool {
+        let user_activity = fold_name(user_activity, raw_activity_names);
+        self.compiled
+            .keywords
+            .get(raw_activity_names)
+            .iter()
+            .any(|keyword| self.matches_folded(&user_activity, keyword))
+    }
+
+    /// Compares an activity name and a keyword that isn't one of the rule's own, e.g. from its
+    /// condition, the way the rule's match mode says
+    pub fn matches_keyword(
+        &self,
+        user_activity: &str,
+        keyword: &str,
+        raw_activity_names: bool,
+    ) -> bool {
+        self.matches_folded(
+            &fold_name(user_activity, raw_activity_names),
+            &fold_name(keyword, raw_activity_names),
+        )
+    }
+
+    /// Both sides folded already
+    fn matches_folded(&self, user_activity: &str, keyword: &str) -> bool {
+        self.match_mode.matches(
+            user_activity,
+            keyword,
+            self.fuzzy_threshold.unwrap_or(DEFAULT_FUZZY_THRESHOLD),
+        )
+    }
+
+    /// The match mode as shown to admins, with the threshold for fuzzy matching
+    fn describe_match_mode(&self) -> String {
+        match self.match_mode {
+            MatchMode::Fuzzy => format!(
+                "fuzzy ({}%)",
+                self.fuzzy_threshold.unwrap_or(DEFAULT_FUZZY_THRESHOLD)
+            ),
+            _ => self.match_mode.to_str().to_string(),
+        }
+    }
+
+    fn matches_name(&self, user_activity: &str, raw_activity_names: bool) -> bool {
+        match self.role_type {
+            RoleType::NamedActivity => self.matches_keywords(user_activity, raw_activity_names),
+            RoleType::Regex => self
+                .compiled
+                .regexes
+                .iter()
+                .any(|regex| regex.is_match(user_activity)),
+            // the other rule types don't match on the activity name
+            _ => false,
+        }
+    }
+}
+
+impl From<Rule> for EmbedField {
+    fn from(val: Rule) -> Self {
+        let activities: Vec<String> = val.activities.iter().map(|x| x.to_string()).collect();
+        let rule_value = match val.role_type {
+            RoleType::NamedActivity => {
+                format!(
+                    "{} match: {}",
+                    val.describe_match_mode(),
+                    activities.join(", ")
+                )
+            }
+            RoleType::Else => "Default Role".to_string(),
+            RoleType::Idle => "Online but not Playing".to_string(),
+            RoleType::Status => "Online Status".to_string(),
+            RoleType::Platform => "Client Platform".to_string(),
+            RoleType::Regex => format!("Regex: {}", activities.join(", ")),
+            RoleType::Streaming if activities.is_empty() => "Streaming anything".to_string(),
+            RoleType::Streaming => format!(
+                "Streaming, {} match: {}",
+                val.describe_match_mode(),
+                activities.join(", ")
+            ),
+            RoleType::Listening => format!(
+                "Listening, {} {} match: {}",
+                val.listening_field.to_str(),
+                val.describe_match_mode(),
+                activities.join(", ")
+            ),
+            RoleType::CustomStatus => format!(
+                "Custom Status text or emoji, {} match: {}",
+                val.describe_match_mode(),
+                activities.join(", ")
+            ),
+        };
+        let mut lines = vec![rule_value];
+        if !val.catalogs.is_empty() {
+            let catalogs: Vec<&str> = val.catalogs.iter().map(String::as_str).collect();
+            lines.push(format!("Catalogs: {}", catalogs.join(", ")));
+        }
+        if !val.application_ids.is_empty() {
+            let application_ids: Vec<String> = val
+                .application_ids
+                .iter()
+                .map(|id| id.to_string())
+                .collect();
+            lines.push(format!("Application IDs: {}", application_ids.join(", ")));
+        }
+        if !val.activity_types.is_empty() {
+            let activity_types: Vec<&str> = val
+                .activity_types
+                .iter()
+                .map(|kind| kind.to_str())
+                .collect();
+            lines.push(format!("Activity Types: {}", activity_types.join(", ")));
+        }
+        if val.verified_only {
+            lines.push("Verified applications only".to_string());
+        }
+        lines.extend(val.rich_presence.describe());
+        lines.extend(val.eligibility.describe());
+        if !val.exclusions.is_empty() {
+            let exclusions: Vec<String> = val.exclusions.iter().cloned().collect();
+            lines.push(format!("Excluding: {}", exclusions.join(", ")));
+        }
+        if let Some(condition) = &val.condition {
+            lines.push(format!("Condition: {}", condition));
+        }
+        if let Some(group) = &val.exclusive_group {
+            lines.push(format!("Group: {}, Priority: {}", group, val.priority));
+        } else if val.priority != 0 {
+            lines.push(format!("Priority: {}", val.priority));
+        }
+        if !val.rule_set.is_empty() {
+            lines.push(format!("Rule Set: {}", val.rule_set));
+        }
+        if !val.statuses.is_empty() {
+            let statuses: Vec<&str> = val.statuses.iter().map(|status| status.to_str()).collect();
+            lines.push(format!("While: {}", statuses.join(", ")));
+        }
+        if !val.platforms.is_empty() {
+            let platforms: Vec<&str> = val
+                .platforms
+                .iter()
+                .map(|platform| platform.to_str())
+                .collect();
+            lines.push(format!("On: {}", platforms.join(", ")));
+        }
+        if !val.schedule.is_empty() {
+            let windows: Vec<String> = val.schedule.iter().map(|w| w.to_string()).collect();
+            lines.push(format!("Scheduled (guild time): {}", windows.join(", ")));
+        }
+        if val.min_session_secs != 0 {
+            lines.push(format!(
+                "After playing for {}",
+                format_duration(val.min_session_secs)
+            ));
+        }
+        if val.linger_secs != 0 {
+            lines.push(format!(
+                "Kept for {} after stopping",
+                format_duration(val.linger_secs)
+            ));
+        }
+        if let Some(expires_at) = val.expires_at {
+            let state = match val.is_expired(Utc::now()) {
+                true => "Expired, archived",
+                false => "Expires",
+            };
+            lines.push(format!("{} <t:{}:f>", state, expires_at.timestamp()));
+        }
+        if let Some(channel_id) = val.announce_channel_id {
+            lines.push(format!("Announcing in <#{}>", channel_id));
+        }
+
+        EmbedField {
+            inline: false,
+            name: val.role_name,
+            value: lines.join("\n"),
+        }
+    }
+}
+
+/// Keeps only the highest priority rule of every exclusive group, ties go to the lower role id
+fn resolve_exclusive_groups(rules: BTreeSet<Rule>) -> BTreeSet<Rule> {
+    let mut winners: BTreeMap<String, Rule> = BTreeMap::new();
+    let mut resolved = BTreeSet::new();
+
+    for rule in rules {
+        match rule.exclusive_group.clone() {
+            None => {
+                resolved.insert(rule);
+            }
+            Some(group) => match winners.get(&group) {
+                Some(winner) if winner.priority >= rule.priority => (),
+                _ => {
+                    winners.insert(group, rule);
+                }
+            },
+        }
+    }
+
+    resolved.extend(winners.into_values());
+    resolved
+}
+
+/// Guild wide settings, stored next to the guild's rules
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct GuildSettings {
+    pub eligibility: Eligibility,
+    /// Only track members who opted in, through the consent panel or `/activity-roles opt-in`
+    pub require_opt_in: bool,
+    /// IANA time zone rule schedules are in, UTC when empty
+    pub time_zone: String,
+    /// Match activity names only lowercased, without the unicode and trademark normalization
+    pub raw_activity_names: bool,
+}
+
+impl GuildSettings {
+    /// Reads `2025-06-01 18:00` or `2025-06-01` in the guild's time zone, or an RFC 3339 timestamp
+    pub fn parse_time(&self, s: &str) -> Option<DateTime<Utc>> {
+        let s = s.trim();
+        if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+            return Some(time.to_utc());
+        }
+
+        let local = NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M")
+            .or_else(|_| {
+                NaiveDate::parse_from_str(s, "%Y-%m-%d")
+                    .map(|date| date.and_hms_opt(0, 0, 0).unwrap_or_default())
+            })
+            .ok()?;
+        self.tz()
+            .from_local_datetime(&local)
+            .earliest()
+            .map(|time| time.to_utc())
+    }
+
+    fn tz(&self) -> Tz {
+        self.time_zone.parse().unwrap_or(Tz::UTC)
+    }
+
+    /// Minutes since monday midnight in the guild's time zone
+    pub fn minute_of_week(&self, now: DateTime<Utc>) -> u32 {
+        let local = now.with_timezone(&self.tz());
+        local.weekday().num_days_from_monday() * MINUTES_PER_DAY
+            + local.hour() * 60
+            + local.minute()
+    }
 }
 
-pub fn rules_to_csv_bytes(rules: &BTreeMap<u64, GuildRules>) -> Result<Vec<u8>> {
-    let mut wtr = csv::Writer::from_writer(Vec::new());
+/// The members' own choices, stored on their own rather than with the guild's rules
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MemberConsent {
+    /// Members who asked for their activity not to be turned into roles
+    pub opted_out: BTreeSet<u64>,
+    pub opted_in: BTreeSet<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct GuildRules {
+    activities_rules: BTreeMap<u64, Rule>,
+    /// Fallback rule of every rule set, keyed by the rule set name
+    default_rules: BTreeMap<String, Rule>,
+    /// Applies to members who are online without any activity the rule counts as playing
+    idle_rule: Option<Rule>,
+    /// Expired rules, kept for reference but never matched
+    archived_rules: BTreeMap<u64, Rule>,
+    /// Activity catalogs created by the guild, rules of any guild can use them by name
+    catalogs: BTreeMap<String, BTreeSet<String>>,
+    /// Canonical activity names and the presence names that stand for them
+    aliases: BTreeMap<String, BTreeSet<String>>,
+    /// Every alias folded like rule keywords, to its canonical name. Set by `index_aliases`
+    canonical_names: BTreeMap<String, String>,
+    settings: GuildSettings,
+    consent: MemberConsent,
+}
+
+impl GuildRules {
+    pub fn new() -> Self {
+        GuildRules {
+            default_rules: BTreeMap::new(),
+            activities_rules: BTreeMap::new(),
+            idle_rule: None,
+            archived_rules: BTreeMap::new(),
+            catalogs: BTreeMap::new(),
+            aliases: BTreeMap::new(),
+            canonical_names: BTreeMap::new(),
+            settings: GuildSettings::default(),
+            consent: MemberConsent::default(),
+        }
+    }
+
+    pub fn settings(&self) -> &GuildSettings {
+        &self.settings
+    }
+
+    pub fn consent(&self) -> &MemberConsent {
+        &self.consent
+    }
+
+    pub fn tracks(&self, user_id: u64) -> bool {
+        !self.consent.opted_out.contains(&user_id)
+            && (!self.settings.require_opt_in || self.consent.opted_in.contains(&user_id))
+    }
+
+    fn filter_rules(&self, keep: impl Fn(&Rule) -> bool) -> GuildRules {
+        GuildRules {
+            activities_rules: self
+                .activities_rules
+                .iter()
+                .filter(|(_, rule)| keep(rule))
+                .map(|(role_id, rule)| (*role_id, rule.clone()))
+                .collect(),
+            default_rules: self
+                .default_rules
+                .iter()
+                .filter(|(_, rule)| keep(rule))
+                .map(|(rule_set, rule)| (rule_set.clone(), rule.clone()))
+                .collect(),
+            idle_rule: self.idle_rule.clone().filter(|rule| keep(rule)),
+            archived_rules: BTreeMap::new(),
+            catalogs: BTreeMap::new(),
+            aliases: self.aliases.clone(),
+            canonical_names: self.canonical_names.clone(),
+            settings: self.settings.clone(),
+            consent: self.consent.clone(),
+        }
+    }
+
+    /// The rules the member is eligible for, none when the guild's eligibility or the member's
+    /// consent rules them out
+    pub fn eligible_rules(&self, member: &MemberInfo, now: DateTime<Utc>) -> GuildRules {
+        let now = epoch_millis(now);
+        if !self.tracks(member.user_id) || !self.settings.eligibility.allows(member, now) {
+            return self.filter_rules(|_| false);
+        }
+
+        self.filter_rules(|rule| rule.eligibility.allows(member, now))
+    }
+
+    /// The rules within their schedule windows and not yet expired at `now`
+    pub fn active_rules(&self, now: DateTime<Utc>) -> GuildRules {
+        let minute_of_week = self.settings.minute_of_week(now);
+        self.filter_rules(|rule| rule.is_scheduled(minute_of_week) && !rule.is_expired(now))
+    }
+
+    /// The rules that have expired by `now` but aren't archived yet
+    pub fn expired_rules(&self, now: DateTime<Utc>) -> Vec<Rule> {
+        self.all_rules()
+            .into_iter()
+            .filter(|rule| rule.is_expired(now))
+            .collect()
+    }
+
+    /// Moves the rule to the archive, where it's kept for reference but never matched
+    pub fn archive_rule(&mut self, role_id: u64) -> Result<()> {
+        let rule = self
+            .get_rule(role_id)
+            .cloned()
+            .ok_or(RoleErrors::NoRulesForRole(role_id))?;
+        self.remove_rule(role_id)?;
+        self.archived_rules.insert(role_id, rule);
+        Ok(())
+    }
+
+    /// The rules in effect, with which time windows of their conditions are open. Members need
+    /// their roles refreshed whenever it changes
+    pub fn schedule_state(&self, now: DateTime<Utc>) -> BTreeSet<(u64, Vec<bool>)> {
+        let minute_of_week = self.settings.minute_of_week(now);
+        self.active_rules(now)
+            .all_rules()
+            .iter()
+            .map(|rule| {
+                let open_windows = rule
+                    .condition
+                    .as_ref()
+                    .map(|condition| condition.open_windows(minute_of_week))
+                    .unwrap_or_default();
+                (rule.role_id, open_windows)
+            })
+            .collect()
+    }
+
+    /// Whether a rule's condition depends on the members' voice channel, only then voice state
+    /// changes need the members refreshed
+    pub fn uses_voice(&self) -> bool {
+        self.all_rules()
+            .iter()
+            .filter_map(|rule| rule.condition.as_ref())
+            .any(Condition::uses_voice)
+    }
+
+    pub fn aliases(&self) -> &BTreeMap<String, BTreeSet<String>> {
+        &self.aliases
+    }
+
+    /// Folds the aliases for lookup, again whenever they or the raw names setting change
+    fn index_aliases(&mut self) {
+        let raw_activity_names = self.settings.raw_activity_names;
+        self.canonical_names = self
+            .aliases
+            .iter()
+            .flat_map(|(canonical, aliases)| {
+                aliases
+                    .iter()
+                    .map(move |alias| (fold_name(alias, raw_activity_names), canonical.clone()))
+            })
+            .collect();
+    }
+
+    /// The canonical name the activity name is an alias of, compared like rule keywords
+    fn canonical_name(&self, name: &str) -> Option<&String> {
+        self.canonical_names
+            .get(&fold_name(name, self.settings.raw_activity_names))
+    }
+
+    /// The presence with aliased activity names replaced by their canonical names, rules are
+    /// matched against it. Only copied when an activity is aliased
+    pub fn with_aliases<'a>(&self, user_presence: &'a UserPresence) -> Cow<'a, UserPresence> {
+        let canonical_names: Vec<Option<&String>> = user_presence
+            .activities
+            .iter()
+            .map(|user_activity| self.canonical_name(&user_activity.name))
+            .collect();
+        if canonical_names.iter().all(Option::is_none) {
+            return Cow::Borrowed(user_presence);
+        }
+
+        let mut user_presence = user_presence.clone();
+        for (user_activity, canonical) in user_presence.activities.iter_mut().zip(canonical_names) {
+            if let Some(canonical) = canonical {
+                user_activity.name = canonical.clone();
+            }
+        }
+        Cow::Owned(user_presence)
+    }
+
+    fn rules_mut(&mut self) -> impl Iterator<Item = &mut Rule> {
+        self.activities_rules
+            .values_mut()
+            .chain(self.default_rules.values_mut())
+            .chain(self.idle_rule.iter_mut())
+            .chain(self.archived_rules.values_mut())
+    }
+
+    pub fn all_rules(&self) -> BTreeSet<Rule> {
+        self.default_rules
+            .values()
+            .chain(self.activities_rules.values())
+            .chain(self.idle_rule.iter())
+            .cloned()
+            .collect()
+    }
+
+    /// The idle rule's activity types decide what counts as playing, Playing by default
+    pub fn matching_idle_rule(&self, user_presence: &UserPresence) -> Option<&Rule> {
+        let status = StatusKind::from(user_presence.status);
+        if status == StatusKind::Offline {
+            return None;
+        }
+
+        self.idle_rule.as_ref().filter(|idle_rule| {
+            idle_rule.allows_status(&status)
+                && idle_rule.allows_platforms(&user_presence.platforms)
+                && !user_presence
+                    .activities
+                    .iter()
+                    .any(|user_activity| idle_rule.applies_to_kind(user_activity.kind))
+        })
+    }
+
+    /// The shortest wait until an activity rule's minimum session is met, if any is pending.
+    /// Expects the presence `with_aliases`
+    pub fn pending_session(
+        &self,
+        user_presence: &UserPresence,
+        now: DateTime<Utc>,
+    ) -> Option<Duration> {
+        let status = StatusKind::from(user_presence.status);
+        let now = epoch_millis(now);
+
+        self.activities_rules
+            .values()
+            .filter(|rule| rule.min_session_secs != 0)
+            .filter(|rule| {
+                rule.allows_status(&status) && rule.allows_platforms(&user_presence.platforms)
+            })
+            .flat_map(|rule| {
+                user_presence
+                    .activities
+                    .iter()
+                    .filter(|user_activity| {
+                        rule.matches_activity(user_activity, self.settings.raw_activity_names)
+                    })
+                    .map(move |user_activity| rule.remaining_session(user_activity, now))
+            })
+            .filter(|remaining| !remaining.is_zero())
+            .min()
+    }
+
+    /// Every rule set is evaluated on its own, a set's default rule applies only when nothing in
+    /// that set matched an activity it counts. Rules with a condition match on it alone, whatever
+    /// the activities, the member is for conditions on roles, voice or join date. Expects the
+    /// presence `with_aliases`
+    pub fn matching_rules(
+        &self,
+        user_presence: &UserPresence,
+        member: &MemberInfo,
+        now: DateTime<Utc>,
+    ) -> BTreeSet<Rule> {
+        let status = StatusKind::from(user_presence.status);
+        let platforms = &user_presence.platforms;
+        let user_activities = &user_presence.activities;
+        let minute_of_week = self.settings.minute_of_week(now);
+        let now = epoch_millis(now);
+
+        let (conditional_rules, plain_rules): (Vec<&Rule>, Vec<&Rule>) = self
+            .activities_rules
+            .values()
+            .partition(|rule| rule.condition.is_some());
+
+        let held_conditions: Vec<(&Rule, Vec<&Activity>)> = conditional_rules
+            .into_iter()
+            .filter(|rule| rule.allows_status(&status) && rule.allows_platforms(platforms))
+            .filter_map(|rule| {
+                let condition = rule.condition.as_ref()?;
+                let context = ConditionContext {
+                    rule,
+                    activities: user_activities,
+                    status: status.clone(),
+                    member,
+                    minute_of_week,
+                    now,
+                    raw_activity_names: self.settings.raw_activity_names,
+                };
+                condition
+                    .holds(&context)
+                    .then(|| (rule, condition.matched_activities(&context)))
+            })
+            .collect();
+        let condition_rules: BTreeSet<Rule> = held_conditions
+            .iter()
+            .map(|(rule, _)| (*rule).clone())
+            .collect();
+
+        let presence_rules: BTreeSet<Rule> = plain_rules
+            .iter()
+            .filter(|rule| rule.matches_presence(&status, platforms))
+            .map(|rule| (*rule).clone())
+            .collect();
+
+        if user_activities.is_empty() {
+            return resolve_exclusive_groups(
+                presence_rules.into_iter().chain(condition_rules).collect(),
+            );
+        };
+
+        let mut matched_activities: Vec<(&Rule, &Activity)> = plain_rules
+            .into_iter()
+            .filter(|rule| rule.allows_status(&status) && rule.allows_platforms(platforms))
+            .flat_map(|rule| {
+                user_activities
+                    .iter()
+                    .filter(move |user_activity| {
+                        rule.matches_activity(user_activity, self.settings.raw_activity_names)
+                            && rule.remaining_session(user_activity, now).is_zero()
+                    })
+                    .map(move |user_activity| (rule, user_activity))
+            })
+            .collect();
+        let activity_rules: BTreeSet<Rule> = matched_activities
+            .iter()
+            .map(|(rule, _)| (*rule).clone())
+            .collect();
+
+        // Conditions only take the default's place for the activities they matched, not for a
+        // role or a join date
+        matched_activities.extend(held_conditions.iter().flat_map(|(rule, activities)| {
+            activities.iter().map(move |activity| (*rule, *activity))
+        }));
+
+        // A stream or a song doesn't take the place of a game, the default rule only gives way to
+        // rules that matched an activity it would have counted itself
+        let default_rules = self
+            .default_rules
+            .iter()
+            .filter(|(rule_set, default_rule)| {
+                !matched_activities.iter().any(|(rule, user_activity)| {
+                    rule.rule_set == **rule_set && default_rule.applies_to_kind(user_activity.kind)
+                })
+            })
+            .filter(|(_, rule)| rule.allows_status(&status) && rule.allows_platforms(platforms))
+            .filter(|(_, rule)| {
+                user_activities
+                    .iter()
+                    .any(|user_activity| rule.applies_to_kind(user_activity.kind))
+            })
+            .map(|(_, rule)| rule.clone());
+
+        resolve_exclusive_groups(
+            activity_rules
+                .into_iter()
+                .chain(presence_rules)
+                .chain(condition_rules)
+                .collect(),
+        )
+        .into_iter()
+        .chain(default_rules)
+        .collect()
+    }
+
+    pub fn get_rule(&self, role_id: u64) -> Option<&Rule> {
+        self.activities_rules
+            .get(&role_id)
+            .or_else(|| {
+                self.default_rules
+                    .values()
+                    .find(|rule| rule.role_id == role_id)
+            })
+            .or_else(|| self.idle_rule.iter().find(|rule| rule.role_id == role_id))
+    }
+
+    pub fn get_rule_mut(&mut self, role_id: u64) -> Option<&mut Rule> {
+        match self.activities_rules.get_mut(&role_id) {
+            Some(rule) => Some(rule),
+            None => self
+                .default_rules
+                .values_mut()
+                .chain(self.idle_rule.iter_mut())
+                .find(|rule| rule.role_id == role_id),
+        }
+    }
+
+    /// Adding a rule for an archived role takes the role out of the archive
+    pub fn add_rule(&mut self, mut rule: Rule) -> Result<()> {
+        rule.compile()?;
+        if self.get_rule(rule.role_id).is_some() {
+            return Err(RoleErrors::RoleAlreadyExists(rule.role_id).into());
+        }
+        let role_id = rule.role_id;
+        self.insert_rule(rule)?;
+        self.archived_rules.remove(&role_id);
+        Ok(())
+    }
+
+    fn insert_rule(&mut self, rule: Rule) -> Result<()> {
+        match rule.role_type {
+            RoleType::NamedActivity
+            | RoleType::Regex
+            | RoleType::Streaming
+            | RoleType::Listening
+            | RoleType::CustomStatus
+            | RoleType::Status
+            | RoleType::Platform => {
+                self.activities_rules.insert(rule.role_id, rule);
+                Ok(())
+            }
+            RoleType::Else => match self.default_rules.contains_key(&rule.rule_set) {
+                true => Err(RoleErrors::DefaultRuleAlreadyExists(rule.role_id).into()),
+                false => {
+                    self.default_rules.insert(rule.rule_set.clone(), rule);
+                    Ok(())
+                }
+            },
+            RoleType::Idle => match &self.idle_rule {
+                Some(_) => Err(RoleErrors::IdleRuleAlreadyExists(rule.role_id).into()),
+                None => {
+                    self.idle_rule = Some(rule);
+                    Ok(())
+                }
+            },
+        }
+    }
+
+    pub fn remove_rule(&mut self, role_id: u64) -> Result<()> {
+        if self.activities_rules.contains_key(&role_id) {
+            self.activities_rules.remove(&role_id);
+            Ok(())
+        } else if let Some(rule_set) = self
+            .default_rules
+            .iter()
+            .find(|(_, rule)| rule.role_id == role_id)
+            .map(|(rule_set, _)| rule_set.clone())
+        {
+            self.default_rules.remove(&rule_set);
+            Ok(())
+        } else if let Some(rule) = &self.idle_rule
+            && rule.role_id == role_id
+        {
+            self.idle_rule = None;
+            Ok(())
+        } else if self.archived_rules.remove(&role_id).is_some() {
+            Ok(())
+        } else {
+            Err(RoleErrors::NoRulesForRole(role_id).into())
+        }
+    }
+
+    /// Replaces the rule with the same role id, the old rule is kept if the new one can't be added
+    pub fn edit_rule(&mut self, rule: Rule) -> Result<()> {
+        let old_rule = self
+            .get_rule(rule.role_id)
+            .cloned()
+            .ok_or(RoleErrors::NoRulesForRole(rule.role_id))?;
+        self.remove_rule(rule.role_id)?;
+
+        match self.add_rule(rule) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                self.add_rule(old_rule)?;
+                Err(e)
+            }
+        }
+    }
+}
+
+impl From<GuildRules> for Vec<EmbedField> {
+    fn from(val: GuildRules) -> Self {
+        val.activities_rules
+            .values()
+            .map(|r| r.clone().into())
+            .chain(val.default_rules.values().map(|r| r.clone().into()))
+            .chain(val.idle_rule.iter().map(|r| r.clone().into()))
+            .chain(val.archived_rules.values().map(|r| r.clone().into()))
+            .collect()
+    }
+}
+
+fn split_csv_list(s: &str) -> BTreeSet<String> {
+    s.split(';')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+fn join_csv_list<T: ToString>(items: impl IntoIterator<Item = T>) -> String {
+    items
+        .into_iter()
+        .map(|item| item.to_string())
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+fn parse_csv_optional_number(s: &str, field_name: &str) -> Result<Option<u64>> {
+    match s.trim() {
+        "" => Ok(None),
+        number => number
+            .parse()
+            .map(Some)
+            .with_context(|| format!("Invalid {}: {}", field_name, number)),
+    }
+}
+
+fn parse_csv_ids(s: &str, field_name: &str) -> Result<BTreeSet<u64>> {
+    split_csv_list(s)
+        .iter()
+        .map(|id| {
+            id.parse()
+                .with_context(|| format!("Invalid {}: {}", field_name, id))
+        })
+        .collect()
+}
+
+/// Parses every entry of a `;` separated cell, naming the column when one is unknown
+fn parse_csv_values<T: Ord>(
+    s: &str,
+    field_name: &str,
+    parse: impl Fn(&str) -> Option<T>,
+) -> Result<BTreeSet<T>> {
+    split_csv_list(s)
+        .iter()
+        .map(|value| parse(value).ok_or(anyhow::anyhow!("Unknown {}: {}", field_name, value)))
+        .collect()
+}
+
+fn eligibility_from_csv(row: &CsvRow) -> Result<Eligibility> {
+    Ok(Eligibility {
+        required_roles: parse_csv_ids(&row.required_roles, "required_roles")?,
+        excluded_roles: parse_csv_ids(&row.excluded_roles, "excluded_roles")?,
+        skip_bots: row.skip_bots.trim() == "true",
+        min_member_days: parse_csv_optional_number(&row.min_member_days, "min_member_days")?
+            .unwrap_or(0),
+    })
+}
+
+impl TryFrom<CsvRow> for Rule {
+    type Error = anyhow::Error;
+
+    fn try_from(row: CsvRow) -> Result<Self> {
+        let guild_id = row.guild_id.parse().context("Invalid guild_id")?;
+        let role_id = row.role_id.parse().context("Invalid role_id")?;
+
+        let role_type = RoleType::from_str(&row.role_type)
+            .ok_or(anyhow::anyhow!("Unknown role_type: {}", row.role_type))?;
+
+        let activities = split_csv_list(&row.activity_names);
+        let catalogs = split_csv_list(&row.catalogs);
+        let exclusions = split_csv_list(&row.exclusion_names);
+        let application_ids = parse_csv_ids(&row.application_ids, "application_id")?;
+        let activity_types =
+            parse_csv_values(&row.activity_types, "activity_type", ActivityKind::from_str)?;
+
+        let priority = match row.priority.trim() {
+            "" => 0,
+            priority => priority
+                .parse()
+                .with_context(|| format!("Invalid priority: {}", priority))?,
+        };
+        let exclusive_group = match row.exclusive_group.trim() {
+            "" => None,
+            group => Some(group.to_string()),
+        };
+
+        let match_mode = MatchMode::from_str(&row.match_mode)
+            .ok_or(anyhow::anyhow!("Unknown match_mode: {}", row.match_mode))?;
+        let fuzzy_threshold = parse_csv_optional_number(&row.fuzzy_threshold, "fuzzy_threshold")?
+            .map(|threshold| {
+                u8::try_from(threshold)
+                    .ok()
+                    .filter(|threshold| *threshold <= 100)
+                    .ok_or(anyhow::anyhow!("Invalid fuzzy_threshold: {}", threshold))
+            })
+            .transpose()?;
+
+        let listening_field = ListeningField::from_str(&row.listening_field).ok_or(
+            anyhow::anyhow!("Unknown listening_field: {}", row.listening_field),
+        )?;
+
+        let statuses = parse_csv_values(&row.statuses, "status", StatusKind::from_str)?;
+        let platforms = parse_csv_values(&row.platforms, "platform", ClientPlatform::from_str)?;
+
+        let eligibility = eligibility_from_csv(&row)?;
+
+        let schedule =
+            parse_csv_values(&row.schedule, "schedule window", ScheduleWindow::from_str)?;
+
+        let min_session_secs =
+            parse_csv_optional_number(&row.min_session_secs, "min_session_secs")?.unwrap_or(0);
+        let linger_secs = parse_csv_optional_number(&row.linger_secs, "linger_secs")?.unwrap_or(0);
+
+        let rich_presence = RichPresenceMatcher {
+            details: split_csv_list(&row.details_keywords),
+            state: split_csv_list(&row.state_keywords),
+            large_text: split_csv_list(&row.large_text_keywords),
+            min_party_size: parse_csv_optional_number(&row.min_party_size, "min_party_size")?,
+            max_party_size: parse_csv_optional_number(&row.max_party_size, "max_party_size")?,
+        };
+
+        let announce_channel_id =
+            parse_csv_optional_number(&row.announce_channel_id, "announce_channel_id")?;
+        let condition = match row.condition.trim() {
+            "" => None,
+            condition => Some(
+                Condition::parse(condition)
+                    .with_context(|| format!("Invalid condition: {}", condition))?,
+            ),
+        };
+        let expires_at = match row.expires_at.trim() {
+            "" => None,
+            expires_at => Some(
+                DateTime::parse_from_rfc3339(expires_at)
+                    .with_context(|| format!("Invalid expires_at: {}", expires_at))?
+                    .to_utc(),
+            ),
+        };
+
+        Ok(Rule {
+            guild_id,
+            guild_name: row.guild_name,
+            role_id,
+            role_name: row.role_name,
+            role_type,
+            activities,
+            catalogs,
+            catalog_activities: BTreeSet::new(),
+            match_mode,
+            fuzzy_threshold,
+            exclusions,
+            application_ids,
+            activity_types,
+            verified_only: row.verified_only.trim() == "true",
+            rich_presence,
+            priority,
+            exclusive_group,
+            rule_set: row.rule_set.trim().to_string(),
+            listening_field,
+            eligibility,
+            statuses,
+            platforms,
+            schedule,
+            min_session_secs,
+            linger_secs,
+            announce_channel_id,
+            expires_at,
+            condition,
+            comments: row.comments,
+            compiled: CompiledKeywords::default(),
+        })
+    }
+}
+
+impl From<Rule> for CsvRow {
+    fn from(val: Rule) -> Self {
+        let mut activities: Vec<String> = val.activities.iter().cloned().collect();
+        activities.sort();
+        CsvRow {
+            guild_id: val.guild_id.to_string(),
+            guild_name: val.guild_name,
+            role_id: val.role_id.to_string(),
+            role_name: val.role_name,
+            role_type: val.role_type.to_str().to_string(),
+            activity_names: activities.join(";"),
+            catalogs: join_csv_list(&val.catalogs),
+            match_mode: val.match_mode.to_str().to_string(),
+            fuzzy_threshold: val
+                .fuzzy_threshold
+                .map(|threshold| threshold.to_string())
+                .unwrap_or_default(),
+            exclusion_names: join_csv_list(&val.exclusions),
+            application_ids: join_csv_list(&val.application_ids),
+            activity_types: join_csv_list(val.activity_types.iter().map(|kind| kind.to_str())),
+            verified_only: val.verified_only.to_string(),
+            details_keywords: join_csv_list(&val.rich_presence.details),
+            state_keywords: join_csv_list(&val.rich_presence.state),
+            large_text_keywords: join_csv_list(&val.rich_presence.large_text),
+            min_party_size: join_csv_list(val.rich_presence.min_party_size),
+            max_party_size: join_csv_list(val.rich_presence.max_party_size),
+            priority: val.priority.to_string(),
+            exclusive_group: val.exclusive_group.unwrap_or_default(),
+            rule_set: val.rule_set,
+            listening_field: val.listening_field.to_str().to_string(),
+            required_roles: join_csv_list(&val.eligibility.required_roles),
+            excluded_roles: join_csv_list(&val.eligibility.excluded_roles),
+            skip_bots: val.eligibility.skip_bots.to_string(),
+            min_member_days: val.eligibility.min_member_days.to_string(),
+            statuses: join_csv_list(val.statuses.iter().map(|status| status.to_str())),
+            platforms: join_csv_list(val.platforms.iter().map(|platform| platform.to_str())),
+            schedule: join_csv_list(&val.schedule),
+            min_session_secs: val.min_session_secs.to_string(),
+            linger_secs: val.linger_secs.to_string(),
+            announce_channel_id: join_csv_list(val.announce_channel_id),
+            expires_at: val
+                .expires_at
+                .map(|expires_at| expires_at.to_rfc3339())
+                .unwrap_or_default(),
+            condition: val
+                .condition
+                .map(|condition| condition.to_string())
+                .unwrap_or_default(),
+            comments: val.comments,
+            // Guild settings columns are only used by the guild settings row
+            ..Default::default()
+        }
+    }
+}
+
+impl From<GuildRules> for Vec<CsvRow> {
+    fn from(val: GuildRules) -> Self {
+        let mut rows: Vec<CsvRow> = val
+            .default_rules
+            .values()
+            .map(|r| r.clone().into())
+            .collect();
+        rows.extend(val.activities_rules.values().map(|r| r.clone().into()));
+        rows.extend(val.idle_rule.map(|r| r.into()));
+        rows.extend(val.archived_rules.into_values().map(|r| CsvRow {
+            archived: true.to_string(),
+            ..r.into()
+        }));
+        rows
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct CsvRow {
+    guild_id: String,
+    guild_name: String,
+    role_id: String,
+    role_name: String,
+
+    #[serde(rename = "type")]
+    role_type: String,
+
+    activity_names: String,
+
+    #[serde(default)]
+    match_mode: String,
+
+    #[serde(default)]
+    fuzzy_threshold: String,
+
+    #[serde(default)]
+    exclusion_names: String,
+
+    #[serde(default)]
+    application_ids: String,
+
+    #[serde(default)]
+    activity_types: String,
+
+    #[serde(default)]
+    verified_only: String,
+
+    #[serde(default)]
+    details_keywords: String,
+
+    #[serde(default)]
+    state_keywords: String,
+
+    #[serde(default)]
+    large_text_keywords: String,
+
+    #[serde(default)]
+    min_party_size: String,
+
+    #[serde(default)]
+    max_party_size: String,
+
+    #[serde(default)]
+    priority: String,
+
+    #[serde(default)]
+    exclusive_group: String,
+
+    #[serde(default)]
+    rule_set: String,
+
+    #[serde(default)]
+    listening_field: String,
+
+    #[serde(default)]
+    required_roles: String,
+
+    #[serde(default)]
+    excluded_roles: String,
+
+    #[serde(default)]
+    skip_bots: String,
+
+    #[serde(default)]
+    min_member_days: String,
+
+    #[serde(default)]
+    statuses: String,
+
+    #[serde(default)]
+    platforms: String,
+
+    #[serde(default)]
+    schedule: String,
+
+    #[serde(default)]
+    min_session_secs: String,
+
+    #[serde(default)]
+    linger_secs: String,
+
+    #[serde(default)]
+    announce_channel_id: String,
+
+    #[serde(default)]
+    expires_at: String,
+
+    #[serde(default)]
+    archived: String,
+
+    #[serde(default)]
+    catalogs: String,
+
+    #[serde(default)]
+    condition: String,
+
+    comments: String,
+}
+
+pub async fn update_roles_names(
+    rules: Arc<RwLock<BTreeMap<u64, GuildRules>>>,
+    guild_roles: Vec<Role>,
+    guild_id: u64,
+    guild_name: String,
+) -> Result<()> {
+    let mut wrtr = rules.write().await;
+    let guild_rules = wrtr
+        .get_mut(&guild_id)
+        .ok_or(RoleErrors::NoRulesForGuild(guild_id))?;
+
+    guild_roles.iter().for_each(|guild_role| {
+        let role_id = guild_role.id.into();
+
+        // update rule's role name
+        let rule = guild_rules.get_rule_mut(role_id);
+        if let Some(rule) = rule {
+            rule.role_name = guild_role.name.to_string();
+            rule.guild_name = guild_name.clone()
+        }
+    });
+
+    Ok(())
+}
+
+/// Changes requested for a rule through `/manage edit`
+#[derive(Debug, Clone, Default)]
+pub struct RuleUpdate {
+    pub add_activities: BTreeSet<String>,
+    pub remove_activities: BTreeSet<String>,
+    pub add_exclusions: BTreeSet<String>,
+    pub remove_exclusions: BTreeSet<String>,
+    pub add_application_ids: BTreeSet<u64>,
+    pub remove_application_ids: BTreeSet<u64>,
+    pub add_activity_types: BTreeSet<ActivityKind>,
+    pub remove_activity_types: BTreeSet<ActivityKind>,
+    pub verified_only: Option<bool>,
+    pub add_details: BTreeSet<String>,
+    pub remove_details: BTreeSet<String>,
+    pub add_state: BTreeSet<String>,
+    pub remove_state: BTreeSet<String>,
+    pub add_large_text: BTreeSet<String>,
+    pub remove_large_text: BTreeSet<String>,
+    /// `Some(None)` removes the bound
+    pub min_party_size: Option<Option<u64>>,
+    pub max_party_size: Option<Option<u64>>,
+    pub match_mode: Option<MatchMode>,
+    /// `Some(None)` resets the rule to the default threshold
+    pub fuzzy_threshold: Option<Option<u8>>,
+    pub priority: Option<i64>,
+    /// `Some(None)` takes the rule out of its exclusive group
+    pub exclusive_group: Option<Option<String>>,
+    pub rule_set: Option<String>,
+    pub listening_field: Option<ListeningField>,
+    pub statuses: Option<BTreeSet<StatusKind>>,
+    pub platforms: Option<BTreeSet<ClientPlatform>>,
+    pub schedule: Option<BTreeSet<ScheduleWindow>>,
+    pub min_session_secs: Option<u64>,
+    pub linger_secs: Option<u64>,
+    pub eligibility: EligibilityUpdate,
+    /// `Some(None)` stops the go-live announcements
+    pub announce_channel_id: Option<Option<u64>>,
+    /// `Some(None)` makes the rule permanent
+    pub expires_at: Option<Option<DateTime<Utc>>>,
+    /// `Some(None)` goes back to matching the activities
+    pub condition: Option<Option<Condition>>,
+    /// `/manage edit` always replaces the comment, an edit without one clears it
+    pub comments: Option<String>,
+    /// Replaces the attached catalogs
+    pub catalogs: Option<BTreeSet<String>>,
+}
+
+/// Changes requested through `/manage eligibility`, for a rule or for the whole guild
+#[derive(Debug, Clone, Default)]
+pub struct EligibilityUpdate {
+    pub add_required_roles: BTreeSet<u64>,
+    pub remove_required_roles: BTreeSet<u64>,
+    pub add_excluded_roles: BTreeSet<u64>,
+    pub remove_excluded_roles: BTreeSet<u64>,
+    pub skip_bots: Option<bool>,
+    pub min_member_days: Option<u64>,
+}
+
+impl EligibilityUpdate {
+    fn apply(self, eligibility: &Eligibility) -> Eligibility {
+        Eligibility {
+            required_roles: apply_set_changes(
+                &eligibility.required_roles,
+                &self.add_required_roles,
+                &self.remove_required_roles,
+            ),
+            excluded_roles: apply_set_changes(
+                &eligibility.excluded_roles,
+                &self.add_excluded_roles,
+                &self.remove_excluded_roles,
+            ),
+            skip_bots: self.skip_bots.unwrap_or(eligibility.skip_bots),
+            min_member_days: self.min_member_days.unwrap_or(eligibility.min_member_days),
+        }
+    }
+}
+
+fn apply_set_changes<T: Ord + Clone>(
+    current: &BTreeSet<T>,
+    to_add: &BTreeSet<T>,
+    to_remove: &BTreeSet<T>,
+) -> BTreeSet<T> {
+    current
+        .union(to_add)
+        .filter(|item| !to_remove.contains(*item))
+        .cloned()
+        .collect()
+}
+
+pub async fn update_role_rule(
+    rules: &Arc<RwLock<BTreeMap<u64, GuildRules>>>,
+    guild_id: u64,
+    role_id: u64,
+    update: RuleUpdate,
+) -> Result<Rule> {
+    let mut wrtr = rules.write().await;
+    // The catalogs may belong to any guild
+    if let Some(catalogs) = &update.catalogs {
+        let all_catalogs = all_catalogs(&wrtr);
+        if let Some(unknown) = catalogs
+            .iter()
+            .find(|name| !all_catalogs.contains_key(*name))
+        {
+            return Err(RoleErrors::UnknownCatalog(unknown.clone()).into());
+        }
+    }
+
+    let guild_rules = wrtr
+        .get_mut(&guild_id)
+        .ok_or(RoleErrors::NoRulesForGuild(guild_id))?;
+
+    let rule = guild_rules
+        .get_rule(role_id)
+        .ok_or(RoleErrors::NoRulesForRole(role_id))?;
+
+    let mut new_rule = rule.clone();
+    new_rule.activities = apply_set_changes(
+        &rule.activities,
+        &update.add_activities,
+        &update.remove_activities,
+    );
+    new_rule.exclusions = apply_set_changes(
+        &rule.exclusions,
+        &update.add_exclusions,
+        &update.remove_exclusions,
+    );
+    new_rule.application_ids = apply_set_changes(
+        &rule.application_ids,
+        &update.add_application_ids,
+        &update.remove_application_ids,
+    );
+    new_rule.activity_types = apply_set_changes(
+        &rule.activity_types,
+        &update.add_activity_types,
+        &update.remove_activity_types,
+    );
+    if let Some(verified_only) = update.verified_only {
+        new_rule.verified_only = verified_only;
+    }
+    new_rule.rich_presence.details = apply_set_changes(
+        &rule.rich_presence.details,
+        &update.add_details,
+        &update.remove_details,
+    );
+    new_rule.rich_presence.state = apply_set_changes(
+        &rule.rich_presence.state,
+        &update.add_state,
+        &update.remove_state,
+    );
+    new_rule.rich_presence.large_text = apply_set_changes(
+        &rule.rich_presence.large_text,
+        &update.add_large_text,
+        &update.remove_large_text,
+    );
+    if let Some(min_party_size) = update.min_party_size {
+        new_rule.rich_presence.min_party_size = min_party_size;
+    }
+    if let Some(max_party_size) = update.max_party_size {
+        new_rule.rich_presence.max_party_size = max_party_size;
+    }
+    if let Some(match_mode) = update.match_mode {
+        new_rule.match_mode = match_mode;
+    }
+    if let Some(fuzzy_threshold) = update.fuzzy_threshold {
+        new_rule.fuzzy_threshold = fuzzy_threshold;
+    }
+    if let Some(priority) = update.priority {
+        new_rule.priority = priority;
+    }
+    if let Some(exclusive_group) = update.exclusive_group {
+        new_rule.exclusive_group = exclusive_group;
+    }
+    if let Some(rule_set) = update.rule_set {
+        new_rule.rule_set = rule_set;
+    }
+    if let Some(listening_field) = update.listening_field {
+        new_rule.listening_field = listening_field;
+    }
+    if let Some(statuses) = update.statuses {
+        new_rule.statuses = statuses;
+    }
+    if let Some(platforms) = update.platforms {
+        new_rule.platforms = platforms;
+    }
+    if let Some(schedule) = update.schedule {
+        new_rule.schedule = schedule;
+    }
+    if let Some(min_session_secs) = update.min_session_secs {
+        new_rule.min_session_secs = min_session_secs;
+    }
+    if let Some(linger_secs) = update.linger_secs {
+        new_rule.linger_secs = linger_secs;
+    }
+    new_rule.eligibility = update.eligibility.apply(&rule.eligibility);
+    if let Some(announce_channel_id) = update.announce_channel_id {
+        new_rule.announce_channel_id = announce_channel_id;
+    }
+    if let Some(expires_at) = update.expires_at {
+        new_rule.expires_at = expires_at;
+    }
+    if let Some(condition) = update.condition {
+        new_rule.condition = condition;
+    }
+    if let Some(comments) = update.comments {
+        new_rule.comments = comments;
+    }
+    if let Some(catalogs) = update.catalogs {
+        new_rule.catalogs = catalogs;
+    }
+    guild_rules.edit_rule(new_rule)?;
+
+    resolve_catalogs(&mut wrtr);
+    wrtr.get(&guild_id)
+        .and_then(|guild_rules| guild_rules.get_rule(role_id))
+        .cloned()
+        .ok_or(RoleErrors::NoRulesForRole(role_id).into())
+}
+
+fn all_catalogs(rules: &BTreeMap<u64, GuildRules>) -> BTreeMap<String, BTreeSet<String>> {
+    rules
+        .values()
+        .flat_map(|guild_rules| guild_rules.catalogs.clone())
+        .collect()
+}
+
+/// Copies the catalogs' activities into every rule using them, whichever guild owns them
+pub fn resolve_catalogs(rules: &mut BTreeMap<u64, GuildRules>) {
+    let all_catalogs = all_catalogs(rules);
+
+    for rule in rules
+        .values_mut()
+        .flat_map(|guild_rules| guild_rules.rules_mut())
+    {
+        rule.catalog_activities = rule
+            .catalogs
+            .iter()
+            .filter_map(|name| all_catalogs.get(name))
+            .flatten()
+            .cloned()
+            .collect();
+        rule.fold_keywords();
+    }
+}
+
+/// Catalog names are shared by all guilds, only the guild that created a catalog can change it
+pub async fn update_catalog(
+    rules: &Arc<RwLock<BTreeMap<u64, GuildRules>>>,
+    guild_id: u64,
+    name: &str,
+    add_activities: BTreeSet<String>,
+    remove_activities: BTreeSet<String>,
+) -> Result<BTreeSet<String>> {
+    let mut wrtr = rules.write().await;
+    if wrtr.iter().any(|(owner_id, guild_rules)| {
+        *owner_id != guild_id && guild_rules.catalogs.contains_key(name)
+    }) {
+        return Err(RoleErrors::CatalogOwnedByOtherGuild(name.to_string()).into());
+    }
+    // Removing activities from a catalog that doesn't exist would create an empty one
+    let exists = wrtr
+        .get(&guild_id)
+        .is_some_and(|guild_rules| guild_rules.catalogs.contains_key(name));
+    if !exists && add_activities.is_empty() {
+        return Err(RoleErrors::UnknownCatalog(name.to_string()).into());
+    }
+
+    let catalogs = &mut wrtr.entry(guild_id).or_insert(GuildRules::new()).catalogs;
+    let activities = catalogs.entry(name.to_string()).or_default();
+    *activities = apply_set_changes(activities, &add_activities, &remove_activities);
+    let activities = activities.clone();
+
+    resolve_catalogs(&mut wrtr);
+    Ok(activities)
+}
+
+/// A catalog can only be deleted once no rule uses it anymore
+pub async fn delete_catalog(
+    rules: &Arc<RwLock<BTreeMap<u64, GuildRules>>>,
+    guild_id: u64,
+    name: &str,
+) -> Result<()> {
+    let mut wrtr = rules.write().await;
+    if wrtr
+        .values()
+        .flat_map(|guild_rules| guild_rules.all_rules())
+        .any(|rule| rule.catalogs.contains(name))
+    {
+        return Err(RoleErrors::CatalogInUse(name.to_string()).into());
+    }
+
+    wrtr.get_mut(&guild_id)
+        .and_then(|guild_rules| guild_rules.catalogs.remove(name))
+        .ok_or(RoleErrors::UnknownCatalog(name.to_string()))?;
+    Ok(())
+}
+
+/// The catalog's activities, whichever guild owns it
+pub async fn get_catalog(
+    rules: &Arc<RwLock<BTreeMap<u64, GuildRules>>>,
+    name: &str,
+) -> Result<BTreeSet<String>> {
+    let rdr = rules.read().await;
+    Ok(all_catalogs(&rdr)
+        .remove(name)
+        .ok_or(RoleErrors::UnknownCatalog(name.to_string()))?)
+}
+
+/// The guild's rules using the catalog, and how many rules of other guilds use it
+pub async fn catalog_users(
+    rules: &Arc<RwLock<BTreeMap<u64, GuildRules>>>,
+    guild_id: u64,
+    name: &str,
+) -> (Vec<Rule>, usize) {
+    let rdr = rules.read().await;
+    let (guild_users, other_users): (Vec<Rule>, Vec<Rule>) = rdr
+        .values()
+        .flat_map(|guild_rules| guild_rules.all_rules())
+        .filter(|rule| rule.catalogs.contains(name))
+        .partition(|rule| rule.guild_id == guild_id);
+    (guild_users, other_users.len())
+}
+
+pub async fn update_guild_eligibility(
+    rules: &Arc<RwLock<BTreeMap<u64, GuildRules>>>,
+    guild_id: u64,
+    update: EligibilityUpdate,
+) -> Result<Eligibility> {
+    let mut wrtr = rules.write().await;
+    let guild_rules = wrtr.entry(guild_id).or_insert(GuildRules::new());
+
+    guild_rules.settings.eligibility = update.apply(&guild_rules.settings.eligibility);
+    Ok(guild_rules.settings.eligibility.clone())
+}
+
+/// Records a member's choice, opting in also lifts an earlier opt out
+pub async fn set_member_consent(
+    rules: &Arc<RwLock<BTreeMap<u64, GuildRules>>>,
+    guild_id: u64,
+    user_id: u64,
+    opt_in: bool,
+) {
+    let mut wrtr = rules.write().await;
+    let consent = &mut wrtr.entry(guild_id).or_insert(GuildRules::new()).consent;
+
+    if opt_in {
+        consent.opted_out.remove(&user_id);
+        consent.opted_in.insert(user_id);
+    } else {
+        consent.opted_in.remove(&user_id);
+        consent.opted_out.insert(user_id);
+    }
+}
+
+pub async fn set_guild_time_zone(
+    rules: &Arc<RwLock<BTreeMap<u64, GuildRules>>>,
+    guild_id: u64,
+    time_zone: Tz,
+) {
+    let mut wrtr = rules.write().await;
+    wrtr.entry(guild_id)
+        .or_insert(GuildRules::new())
+        .settings
+        .time_zone = time_zone.name().to_string();
+}
+
+/// An alias stands for a single canonical name, adding it moves it away from any other one
+pub async fn add_aliases(
+    rules: &Arc<RwLock<BTreeMap<u64, GuildRules>>>,
+    guild_id: u64,
+    canonical: &str,
+    aliases: BTreeSet<String>,
+) -> BTreeSet<String> {
+    let mut wrtr = rules.write().await;
+    let guild_rules = wrtr.entry(guild_id).or_insert(GuildRules::new());
+    let raw_activity_names = guild_rules.settings.raw_activity_names;
+    let folded: BTreeSet<String> = aliases
+        .iter()
+        .map(|alias| fold_name(alias, raw_activity_names))
+        .collect();
+
+    let guild_aliases = &mut guild_rules.aliases;
+    for other_aliases in guild_aliases.values_mut() {
+        other_aliases.retain(|alias| !folded.contains(&fold_name(alias, raw_activity_names)));
+    }
+    guild_aliases.retain(|_, other_aliases| !other_aliases.is_empty());
+
+    let canonical_aliases = guild_aliases.entry(canonical.to_string()).or_default();
+    canonical_aliases.extend(aliases);
+    let canonical_aliases = canonical_aliases.clone();
+    guild_rules.index_aliases();
+    canonical_aliases
+}
+
+/// Removes the aliases, a canonical name left without aliases is dropped
+pub async fn remove_aliases(
+    rules: &Arc<RwLock<BTreeMap<u64, GuildRules>>>,
+    guild_id: u64,
+    aliases: &BTreeSet<String>,
+) -> Result<()> {
+    let mut wrtr = rules.write().await;
+    let guild_rules = wrtr
+        .get_mut(&guild_id)
+        .ok_or(RoleErrors::NoRulesForGuild(guild_id))?;
+    let raw_activity_names = guild_rules.settings.raw_activity_names;
+
+    if let Some(unknown) = aliases
+        .iter()
+        .find(|alias| guild_rules.canonical_name(alias).is_none())
+    {
+        return Err(RoleErrors::UnknownAlias(unknown.clone()).into());
+    }
+
+    let folded: BTreeSet<String> = aliases
+        .iter()
+        .map(|alias| fold_name(alias, raw_activity_names))
+        .collect();
+    let guild_aliases = &mut guild_rules.aliases;
+    for canonical_aliases in guild_aliases.values_mut() {
+        canonical_aliases.retain(|alias| !folded.contains(&fold_name(alias, raw_activity_names)));
+    }
+    guild_aliases.retain(|_, canonical_aliases| !canonical_aliases.is_empty());
+    guild_rules.index_aliases();
+    Ok(())
+}
+
+pub async fn set_raw_activity_names(
+    rules: &Arc<RwLock<BTreeMap<u64, GuildRules>>>,
+    guild_id: u64,
+    raw_activity_names: bool,
+) {
+    let mut wrtr = rules.write().await;
+    let guild_rules = wrtr.entry(guild_id).or_insert(GuildRules::new());
+    guild_rules.settings.raw_activity_names = raw_activity_names;
+    guild_rules.index_aliases();
+}
+
+pub async fn set_require_opt_in(
+    rules: &Arc<RwLock<BTreeMap<u64, GuildRules>>>,
+    guild_id: u64,
+    require_opt_in: bool,
+) {
+    let mut wrtr = rules.write().await;
+    wrtr.entry(guild_id)
+        .or_insert(GuildRules::new())
+        .settings
+        .require_opt_in = require_opt_in;
+}
+
+pub fn load_rules_from_buffer<R: Read>(reader: R) -> Result<BTreeMap<u64, GuildRules>> {
+    let mut reader_buffer = csv::Reader::from_reader(reader);
+    let mut rules = BTreeMap::new();
+
+    for result in reader_buffer.deserialize() {
+        let row: CsvRow = result?;
+        let archived = row.archived.trim() == "true";
+        let context = format!(
+            "Invalid rule for role {} in guild {}",
+            row.role_id, row.guild_id
+        );
+        let mut rule = Rule::try_from(row).context(context.clone())?;
+        rule.compile().context(context)?;
+
+        let guild_rules = rules.entry(rule.guild_id).or_insert(GuildRules::new());
+        if archived {
+            guild_rules.archived_rules.insert(rule.role_id, rule);
+            continue;
+        }
+
+        match rule.role_type {
+            RoleType::NamedActivity
+            | RoleType::Regex
+            | RoleType::Streaming
+            | RoleType::Listening
+            | RoleType::CustomStatus
+            | RoleType::Status
+            | RoleType::Platform => {
+                guild_rules.activities_rules.insert(rule.role_id, rule);
+            }
+            RoleType::Else => {
+                guild_rules
+                    .default_rules
+                    .insert(rule.rule_set.clone(), rule);
+            }
+            RoleType::Idle => {
+                guild_rules.idle_rule = Some(rule);
+            }
+        }
+    }
+
+    Ok(rules)
+}
+
+pub fn load_rules_from_file(file_path: String) -> Result<BTreeMap<u64, GuildRules>> {
+    let file = File::open(file_path)?;
+    load_rules_from_buffer(BufReader::new(file))
+}
+
+/// Loads the rules with the guilds' settings, catalogs and aliases kept next to them
+pub fn load_db_from_file() -> Result<BTreeMap<u64, GuildRules>> {
+    let mut rules = load_rules_from_file("db/db.csv".to_string()).context("Invalid db/db.csv")?;
+    load_guild_files(&mut rules)?;
+    Ok(rules)
+}
+
+pub async fn load_rules_from_github(
+    github_config: &GithubConfig,
+) -> Result<BTreeMap<u64, GuildRules>> {
+    load_rules_from_buffer(
+        get_bytes_from_github(
+            &github_config.owner,
+            &github_config.repo,
+            &github_config.path,
+            &github_config.branch,
+        )
+        .await?
+        .as_slice(),
+    )
+}
+
+pub fn rules_to_csv_bytes(rules: &BTreeMap<u64, GuildRules>) -> Result<Vec<u8>> {
+    let mut wtr = csv::Writer::from_writer(Vec::new());
+
+    // Collect all rules from all guilds
+    let mut all_csv_rows: Vec<CsvRow> = rules
+        .values()
+        .flat_map(|guild_rules| Into::<Vec<CsvRow>>::into(guild_rules.clone()))
+        .collect();
+    // Sort by guild_id then by role_name for consistent output
+    all_csv_rows.sort_by(|a, b| match a.guild_id.cmp(&b.guild_id) {
+        std::cmp::Ordering::Equal => a.role_id.cmp(&b.role_id),
+        o => o,
+    });
+
+    // Write all rows
+    for row in all_csv_rows {
+        wtr.serialize(row)?;
+    }
+
+    wtr.flush()?;
+    Ok(wtr.into_inner()?)
+}
+
+pub fn save_rules_to_file(rules: &BTreeMap<u64, GuildRules>, file_path: String) -> Result<()> {
+    let csv_bytes = rules_to_csv_bytes(rules)?;
+    std::fs::write(file_path, csv_bytes)?;
+    Ok(())
+}
+
+/// Member consent is kept out of the rules file, which gets uploaded to github
+const CONSENT_FILE: &str = "db/consent.csv";
+
+/// Guild settings, catalogs and aliases each have their own file next to the rules, they stay
+/// local like the consent
+const SETTINGS_FILE: &str = "db/settings.csv";
+const CATALOGS_FILE: &str = "db/catalogs.csv";
+const ALIASES_FILE: &str = "db/aliases.csv";
+
+/// Saves the rules and every file kept next to them
+pub fn save_db_to_file(rules: &BTreeMap<u64, GuildRules>) -> Result<()> {
+    save_rules_to_file(rules, "db/db.csv".to_string())?;
+    save_consent_to_file(rules, CONSENT_FILE.to_string())?;
+    std::fs::write(SETTINGS_FILE, settings_to_csv_bytes(rules)?)?;
+    std::fs::write(CATALOGS_FILE, catalogs_to_csv_bytes(rules)?)?;
+    std::fs::write(ALIASES_FILE, aliases_to_csv_bytes(rules)?)?;
+    Ok(())
+}
+
+/// One member's choice in a guild
+#[derive(Debug, Serialize, Deserialize)]
+struct ConsentRow {
+    guild_id: u64,
+    user_id: u64,
+    opted_in: bool,
+}
+
+pub fn consent_to_csv_bytes(rules: &BTreeMap<u64, GuildRules>) -> Result<Vec<u8>> {
+    let mut wtr = csv::Writer::from_writer(Vec::new());
+    for (guild_id, guild_rules) in rules {
+        for (opted_in, user_ids) in [
+            (false, &guild_rules.consent.opted_out),
+            (true, &guild_rules.consent.opted_in),
+        ] {
+            for user_id in user_ids {
+                wtr.serialize(ConsentRow {
+                    guild_id: *guild_id,
+                    user_id: *user_id,
+                    opted_in,
+                })?;
+            }
+        }
+    }
+
+    wtr.flush()?;
+    Ok(wtr.into_inner()?)
+}
+
+/// Reads the members' consent into the guilds' rules
+pub fn load_consent_from_buffer<R: Read>(
+    rules: &mut BTreeMap<u64, GuildRules>,
+    reader: R,
+) -> Result<()> {
+    for result in csv::Reader::from_reader(reader).deserialize() {
+        let row: ConsentRow = result?;
+        let consent = &mut rules
+            .entry(row.guild_id)
+            .or_insert(GuildRules::new())
+            .consent;
+        match row.opted_in {
+            true => consent.opted_in.insert(row.user_id),
+            false => consent.opted_out.insert(row.user_id),
+        };
+    }
+    Ok(())
+}
+
+pub fn save_consent_to_file(rules: &BTreeMap<u64, GuildRules>, file_path: String) -> Result<()> {
+    std::fs::write(file_path, consent_to_csv_bytes(rules)?)?;
+    Ok(())
+}
+
+/// Rules loaded from storage come without consent, the members' choices carry over from the
+/// rules they replace
+pub fn keep_consent(
+    old_rules: &BTreeMap<u64, GuildRules>,
+    new_rules: &mut BTreeMap<u64, GuildRules>,
+) {
+    for (guild_id, guild_rules) in old_rules {
+        if guild_rules.consent != MemberConsent::default() {
+            new_rules
+                .entry(*guild_id)
+                .or_insert(GuildRules::new())
+                .consent = guild_rules.consent.clone();
+        }
+    }
+}
+
+/// A guild's settings, only guilds that changed them have a row
+#[derive(Debug, Serialize, Deserialize)]
+struct SettingsRow {
+    guild_id: u64,
+    required_roles: String,
+    excluded_roles: String,
+    skip_bots: bool,
+    min_member_days: u64,
+    require_opt_in: bool,
+    time_zone: String,
+    raw_activity_names: bool,
+}
+
+/// A catalog and the activities in it
+#[derive(Debug, Serialize, Deserialize)]
+struct CatalogRow {
+    guild_id: u64,
+    name: String,
+    activities: String,
+}
+
+/// An activity's canonical name and the names it's also known by
+#[derive(Debug, Serialize, Deserialize)]
+struct AliasRow {
+    guild_id: u64,
+    canonical: String,
+    aliases: String,
+}
+
+pub fn settings_to_csv_bytes(rules: &BTreeMap<u64, GuildRules>) -> Result<Vec<u8>> {
+    let mut wtr = csv::Writer::from_writer(Vec::new());
+    for (guild_id, guild_rules) in rules {
+        let settings = &guild_rules.settings;
+        if *settings == GuildSettings::default() {
+            continue;
+        }
+        wtr.serialize(SettingsRow {
+            guild_id: *guild_id,
+            required_roles: join_csv_list(&settings.eligibility.required_roles),
+            excluded_roles: join_csv_list(&settings.eligibility.excluded_roles),
+            skip_bots: settings.eligibility.skip_bots,
+            min_member_days: settings.eligibility.min_member_days,
+            require_opt_in: settings.require_opt_in,
+            time_zone: settings.time_zone.clone(),
+            raw_activity_names: settings.raw_activity_names,
+        })?;
+    }
+
+    wtr.flush()?;
+    Ok(wtr.into_inner()?)
+}
+
+/// Reads the guilds' settings into their rules, `index_guild_data` has to run once the aliases
+/// are in too
+pub fn load_settings_from_buffer<R: Read>(
+    rules: &mut BTreeMap<u64, GuildRules>,
+    reader: R,
+) -> Result<()> {
+    for result in csv::Reader::from_reader(reader).deserialize() {
+        let row: SettingsRow = result?;
+        let context = format!("Invalid settings for guild {}", row.guild_id);
+        rules
+            .entry(row.guild_id)
+            .or_insert(GuildRules::new())
+            .settings = GuildSettings {
+            eligibility: Eligibility {
+                required_roles: parse_csv_ids(&row.required_roles, "required_roles")
+                    .context(context.clone())?,
+                excluded_roles: parse_csv_ids(&row.excluded_roles, "excluded_roles")
+                    .context(context)?,
+                skip_bots: row.skip_bots,
+                min_member_days: row.min_member_days,
+            },
+            require_opt_in: row.require_opt_in,
+            time_zone: row.time_zone.trim().to_string(),
+            raw_activity_names: row.raw_activity_names,
+        };
+    }
+    Ok(())
+}
+
+pub fn catalogs_to_csv_bytes(rules: &BTreeMap<u64, GuildRules>) -> Result<Vec<u8>> {
+    let mut wtr = csv::Writer::from_writer(Vec::new());
+    for (guild_id, guild_rules) in rules {
+        for (name, activities) in &guild_rules.catalogs {
+            wtr.serialize(CatalogRow {
+                guild_id: *guild_id,
+                name: name.clone(),
+                activities: join_csv_list(activities),
+            })?;
+        }
+    }
+
+    wtr.flush()?;
+    Ok(wtr.into_inner()?)
+}
+
+/// Reads the catalogs into the guilds that own them, `index_guild_data` resolves them for the
+/// rules
+pub fn load_catalogs_from_buffer<R: Read>(
+    rules: &mut BTreeMap<u64, GuildRules>,
+    reader: R,
+) -> Result<()> {
+    for result in csv::Reader::from_reader(reader).deserialize() {
+        let row: CatalogRow = result?;
+        rules
+            .entry(row.guild_id)
+            .or_insert(GuildRules::new())
+            .catalogs
+            .insert(row.name, split_csv_list(&row.activities));
+    }
+    Ok(())
+}
+
+pub fn aliases_to_csv_bytes(rules: &BTreeMap<u64, GuildRules>) -> Result<Vec<u8>> {
+    let mut wtr = csv::Writer::from_writer(Vec::new());
+    for (guild_id, guild_rules) in rules {
+        for (canonical, aliases) in &guild_rules.aliases {
+            wtr.serialize(AliasRow {
+                guild_id: *guild_id,
+                canonical: canonical.clone(),
+                aliases: join_csv_list(aliases),
+            })?;
+        }
+    }
+
+    wtr.flush()?;
+    Ok(wtr.into_inner()?)
+}
+
+/// Reads the aliases into the guilds' rules, `index_guild_data` folds them for lookup
+pub fn load_aliases_from_buffer<R: Read>(
+    rules: &mut BTreeMap<u64, GuildRules>,
+    reader: R,
+) -> Result<()> {
+    for result in csv::Reader::from_reader(reader).deserialize() {
+        let row: AliasRow = result?;
+        rules
+            .entry(row.guild_id)
+            .or_insert(GuildRules::new())
+            .aliases
+            .insert(row.canonical, split_csv_list(&row.aliases));
+    }
+    Ok(())
+}
+
+/// Indexes the aliases and resolves the catalogs, once the rules and everything kept next to
+/// them are loaded
+pub fn index_guild_data(rules: &mut BTreeMap<u64, GuildRules>) {
+    rules.values_mut().for_each(GuildRules::index_aliases);
+    resolve_catalogs(rules);
+}
+
+/// Reads a file kept next to the rules, a missing one has nothing in it yet
+fn load_file_if_exists(
+    file_path: &str,
+    load: impl FnOnce(BufReader<File>) -> Result<()>,
+) -> Result<()> {
+    if !std::path::Path::new(file_path).exists() {
+        return Ok(());
+    }
+    load(BufReader::new(File::open(file_path)?)).with_context(|| format!("Invalid {}", file_path))
+}
+
+fn load_guild_files(rules: &mut BTreeMap<u64, GuildRules>) -> Result<()> {
+    load_file_if_exists(SETTINGS_FILE, |reader| {
+        load_settings_from_buffer(rules, reader)
+    })?;
+    load_file_if_exists(CATALOGS_FILE, |reader| {
+        load_catalogs_from_buffer(rules, reader)
+    })?;
+    load_file_if_exists(ALIASES_FILE, |reader| {
+        load_aliases_from_buffer(rules, reader)
+    })?;
+    index_guild_data(rules);
+    Ok(())
+}
+
+/// Rules loaded from github come without the guilds' settings, catalogs and aliases, they
+/// carry over from the rules they replace
+pub fn keep_guild_data(
+    old_rules: &BTreeMap<u64, GuildRules>,
+    new_rules: &mut BTreeMap<u64, GuildRules>,
+) {
+    for (guild_id, guild_rules) in old_rules {
+        if guild_rules.settings != GuildSettings::default()
+            || !guild_rules.catalogs.is_empty()
+            || !guild_rules.aliases.is_empty()
+        {
+            let new_guild_rules = new_rules.entry(*guild_id).or_insert(GuildRules::new());
+            new_guild_rules.settings = guild_rules.settings.clone();
+            new_guild_rules.catalogs = guild_rules.catalogs.clone();
+            new_guild_rules.aliases = guild_rules.aliases.clone();
+        }
+    }
+    index_guild_data(new_rules);
+}
+
+pub async fn save_current_db_to_file(rules: Arc<RwLock<BTreeMap<u64, GuildRules>>>) -> Result<()> {
+    let rules = rules.read().await;
+    save_db_to_file(&rules)
+}
+
+pub async fn save_db_to_github(
+    rules: &BTreeMap<u64, GuildRules>,
+    github_config: &GithubConfig,
+) -> Result<()> {
+    let csv_bytes = rules_to_csv_bytes(rules)?;
+    let bytes = Bytes::from(csv_bytes);
+
+    upload_bytes_to_github(
+        &bytes,
+        &github_config.owner,
+        &github_config.repo,
+        &github_config.path,
+        &github_config.branch,
+    )
+    .await
+}
+
+pub async fn load_db(github_config: Option<&GithubConfig>) -> BTreeMap<u64, GuildRules> {
+    let mut db = load_rules_db(github_config).await;
+    if let Err(e) = load_file_if_exists(CONSENT_FILE, |reader| {
+        load_consent_from_buffer(&mut db, reader)
+    }) {
+        // Starting without it would overwrite the broken file on the next save
+        panic!("{:#}", e)
+    }
+    db
+}
+
+async fn load_rules_db(github_config: Option<&GithubConfig>) -> BTreeMap<u64, GuildRules> {
+    let local_db = load_db_from_file();
+    if let Ok(db) = local_db {
+        db
+    } else if let Err(e) = local_db
+        && std::path::Path::new("db/db.csv").exists()
+    {
+        // Starting without it would overwrite the broken file on the next save
+        panic!("{:#}", e)
+    } else if let Some(github_config) = github_config
+        && let Ok(mut db) = load_rules_from_github(github_config).await
+    {
+        save_rules_to_file(&db, "db/db.csv".to_string()).unwrap();
+        if let Err(e) = load_guild_files(&mut db) {
+            panic!("{:#}", e)
+        }
+        db
+    } else {
+        BTreeMap::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{config_handler, github_handler};
+
+    #[allow(unused_imports)]
+    use super::*;
+    use twilight_model::{
+        gateway::presence::{ActivityEmoji, ActivityParty, ActivityTimestamps, MinimalActivity},
+        id::Id,
+    };
+
+    /// Saves and loads the rules with everything kept next to them, as the db files would
+    fn reload(rules: &BTreeMap<u64, GuildRules>) -> BTreeMap<u64, GuildRules> {
+        let mut reloaded = load_rules_from_buffer(&rules_to_csv_bytes(rules).unwrap()[..]).unwrap();
+        load_settings_from_buffer(&mut reloaded, &settings_to_csv_bytes(rules).unwrap()[..])
+            .unwrap();
+        load_catalogs_from_buffer(&mut reloaded, &catalogs_to_csv_bytes(rules).unwrap()[..])
+            .unwrap();
+        load_aliases_from_buffer(&mut reloaded, &aliases_to_csv_bytes(rules).unwrap()[..]).unwrap();
+        index_guild_data(&mut reloaded);
+        reloaded
+    }
+
+    fn activity(name: &str) -> Activity {
+        activity_of_kind(name, ActivityType::Playing)
+    }
+
+    fn activity_of_kind(name: &str, kind: ActivityType) -> Activity {
+        MinimalActivity {
+            kind,
+            name: name.to_string(),
+            url: None,
+        }
+        .into()
+    }
+
+    fn activities(names: &[&str]) -> Vec<Activity> {
+        names.iter().map(|name| activity(name)).collect()
+    }
+
+    fn rule(role_id: u64, role_type: RoleType) -> Rule {
+        Rule::new(
+            0,
+            "guild_name".to_string(),
+            role_id,
+            "role".to_string(),
+            role_type,
+        )
+    }
+
+    /// Rules match with what `compile` builds, as they do once added to a guild
+    fn compiled(mut rule: Rule) -> Rule {
+        rule.compile().unwrap();
+        rule
+    }
+
+    fn now_millis() -> u64 {
+        epoch_millis(Utc::now())
+    }
+
+    fn role_ids(rules: &BTreeSet<Rule>) -> BTreeSet<u64> {
+        rules.iter().map(|rule| rule.role_id).collect()
+    }
+
+    fn online(activities: Vec<Activity>) -> UserPresence {
+        UserPresence {
+            activities,
+            status: Status::Online,
+            platforms: [ClientPlatform::Desktop].into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_save_db_to_file() {
+        let _ = save_rules_to_file(&load_db(None).await, "db_test.csv".to_string());
+    }
+
+    #[tokio::test]
+    async fn test_save_db_equals() {
+        assert_eq!(
+            load_rules_from_file("db.csv".to_string()).unwrap(),
+            load_rules_from_github(&GithubConfig::new().unwrap())
+                .await
+                .unwrap()
+        )
+    }
+
+    #[test]
+    fn test_rule_named_activity() {
+        let row = CsvRow {
+            guild_id: "0".to_string(),
+            guild_name: "guild_name".to_string(),
+            role_id: "0".to_string(),
+            role_name: "role1".to_string(),
+            role_type: "named-activity".to_string(),
+            activity_names: "Game1;Game2".to_string(),
+            comments: "".to_string(),
+            ..Default::default()
+        };
+        let rule: Rule = row.try_into().unwrap();
+        assert_eq!(
+            rule,
+            Rule {
+                guild_id: 0,
+                guild_name: "guild_name".to_string(),
+                role_id: 0,
+                role_name: "role1".to_string(),
+                role_type: RoleType::NamedActivity,
+                activities: ["Game1", "Game2"].iter().map(|s| s.to_string()).collect(),
+                comments: "".to_string(),
+                ..self::rule(0, RoleType::NamedActivity)
+            }
+        )
+    }
+
+    #[test]
+    fn test_rule_else() {
+        let row = CsvRow {
+            guild_id: "0".to_string(),
+            guild_name: "guild_name".to_string(),
+            role_id: "0".to_string(),
+            role_name: "role1".to_string(),
+            role_type: "else".to_string(),
+            activity_names: "Game1;Game2".to_string(),
+            comments: "".to_string(),
+            ..Default::default()
+        };
+        let rule: Rule = row.try_into().unwrap();
+        assert_eq!(
+            rule,
+            Rule {
+                guild_id: 0,
+                guild_name: "guild_name".to_string(),
+                role_id: 0,
+                role_name: "role1".to_string(),
+                role_type: RoleType::Else,
+                activities: ["Game1", "Game2"].iter().map(|s| s.to_string()).collect(),
+                comments: "".to_string(),
+                ..self::rule(0, RoleType::Else)
+            }
+        )
+    }
+
+    #[test]
+    fn test_guild_rules_activity() {
+        let named_rule = compiled(Rule {
+            guild_id: 0,
+            guild_name: "guild_name".to_string(),
+            role_id: 0,
+            role_name: "role1".to_string(),
+            role_type: RoleType::NamedActivity,
+            activities: ["Game1", "Game2"].iter().map(|s| s.to_string()).collect(),
+            comments: "".to_string(),
+            ..rule(0, RoleType::NamedActivity)
+        });
+        let else_rule = Rule {
+            guild_id: 0,
+            guild_name: "guild_name".to_string(),
+            role_id: 0,
+            role_name: "role1".to_string(),
+            role_type: RoleType::Else,
+            activities: ["Game1", "Game2"].iter().map(|s| s.to_string()).collect(),
+            comments: "".to_string(),
+            ..rule(0, RoleType::Else)
+        };
+
+        let mut guild_rules = GuildRules::new();
+        guild_rules
+            .activities_rules
+            .insert(named_rule.role_id, named_rule);
+        guild_rules
+            .default_rules
+            .insert(else_rule.rule_set.clone(), else_rule);
+
+        let user_activities = activities(&["AGame1"]);
+
+        assert_eq!(
+            guild_rules.matching_rules(
+                &online(user_activities.clone()),
+                &MemberInfo::default(),
+                Utc::now()
+            ),
+            guild_rules.activities_rules.values().cloned().collect()
+        );
+
+        let user_activities = activities(&["asd"]);
+
+        assert_eq!(
+            guild_rules.matching_rules(
+                &online(user_activities.clone()),
+                &MemberInfo::default(),
+                Utc::now()
+            ),
+            guild_rules.default_rules.values().cloned().collect()
+        );
+    }
+
+    #[test]
+    fn test_guild_rules_regex() {
+        let regex_rule = Rule {
+            guild_id: 0,
+            guild_name: "guild_name".to_string(),
+            role_name: "role1".to_string(),
+            activities: ["^street fighter( \\d+)?$".to_string()].into(),
+            comments: "".to_string(),
+            ..rule(1, RoleType::Regex)
+        };
+
+        let mut guild_rules = GuildRules::new();
+        guild_rules.add_rule(regex_rule.clone()).unwrap();
+
+        assert_eq!(
+            guild_rules.matching_rules(
+                &online(activities(&["Street Fighter 6"])),
+                &MemberInfo::default(),
+                Utc::now()
+            ),
+            BTreeSet::from([regex_rule])
+        );
+
+        assert!(
+            guild_rules
+                .matching_rules(
+                    &online(activities(&["Street Fighter Collection Viewer"])),
+                    &MemberInfo::default(),
+                    Utc::now()
+                )
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_rule_exclusions() {
+        let rule = compiled(Rule {
+            guild_id: 0,
+            guild_name: "guild_name".to_string(),
+            role_name: "Currently Quaking".to_string(),
+            activities: ["quake".to_string()].into(),
+            exclusions: ["Live Demo Viewer".to_string()].into(),
+            comments: "".to_string(),
+            ..rule(0, RoleType::NamedActivity)
+        });
+
+        assert!(rule.matches_activity(&activity("Quake Live"), false));
+        assert!(!rule.matches_activity(&activity("Quake Live Demo Viewer"), false));
+
+        let mut guild_rules = GuildRules::new();
+        guild_rules.add_rule(rule).unwrap();
+        assert!(
+            guild_rules
+                .matching_rules(
+                    &online(activities(&["Quake Live Demo Viewer"])),
+                    &MemberInfo::default(),
+                    Utc::now()
+                )
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_exclusive_groups() {
+        let grouped_rule = |role_id, activity: &str, priority, group: Option<&str>| Rule {
+            activities: [activity.to_string()].into(),
+            priority,
+            exclusive_group: group.map(|g| g.to_string()),
+            ..rule(role_id, RoleType::NamedActivity)
+        };
+
+        let mut guild_rules = GuildRules::new();
+        guild_rules
+            .add_rule(grouped_rule(1, "quake", 10, Some("main")))
+            .unwrap();
+        guild_rules
+            .add_rule(grouped_rule(2, "tekken", 5, Some("main")))
+            .unwrap();
+        guild_rules
+            .add_rule(grouped_rule(3, "tekken", 0, None))
+            .unwrap();
+
+        let matched = role_ids(&guild_rules.matching_rules(
+            &online(activities(&["Quake Champions", "Tekken 8"])),
+            &MemberInfo::default(),
+            Utc::now(),
+        ));
+        assert_eq!(matched, BTreeSet::from([1, 3]));
+
+        let matched = role_ids(&guild_rules.matching_rules(
+            &online(activities(&["Tekken 8"])),
+            &MemberInfo::default(),
+            Utc::now(),
+        ));
+        assert_eq!(matched, BTreeSet::from([2, 3]));
+    }
+
+    #[test]
+    fn test_rule_sets() {
+        let set_rule = |role_id, role_type, activity: &str, rule_set: &str| Rule {
+            activities: [activity.to_string()].into(),
+            rule_set: rule_set.to_string(),
+            ..rule(role_id, role_type)
+        };
+
+        let mut guild_rules = GuildRules::new();
+        guild_rules
+            .add_rule(set_rule(1, RoleType::NamedActivity, "tekken", "genre"))
+            .unwrap();
+        guild_rules
+            .add_rule(set_rule(2, RoleType::Else, "", "genre"))
+            .unwrap();
+        guild_rules
+            .add_rule(set_rule(3, RoleType::NamedActivity, "steam", "platform"))
+            .unwrap();
+        guild_rules
+            .add_rule(set_rule(4, RoleType::Else, "", "platform"))
+            .unwrap();
+        assert!(
+            guild_rules
+                .add_rule(set_rule(5, RoleType::Else, "", "platform"))
+                .is_err()
+        );
+
+        let matched = |names: &[&str]| {
+            role_ids(&guild_rules.matching_rules(
+                &online(activities(names)),
+                &MemberInfo::default(),
+                Utc::now(),
+            ))
+        };
+        assert_eq!(matched(&["Tekken 8"]), [1, 4].into());
+        assert_eq!(matched(&["Steam Deck"]), [2, 3].into());
+        assert_eq!(matched(&["Minecraft"]), [2, 4].into());
+    }
+
+    #[test]
+    fn test_application_ids() {
+        let rule = compiled(Rule {
+            activities: ["quake".to_string()].into(),
+            application_ids: [1234].into(),
+            verified_only: true,
+            ..rule(0, RoleType::NamedActivity)
+        });
+
+        let custom_status = activity("Playing Quake");
+        assert!(!rule.matches_activity(&custom_status, false));
+
+        let mut verified = activity("Quake Champions");
+        verified.application_id = Some(Id::new(1));
+        assert!(rule.matches_activity(&verified, false));
+
+        let mut renamed = activity("QC");
+        renamed.application_id = Some(Id::new(1234));
+        assert!(rule.matches_activity(&renamed, false));
+    }
+
+    #[test]
+    fn test_activity_types() {
+        let mut guild_rules = GuildRules::new();
+        guild_rules
+            .add_rule(Rule {
+                activities: ["quake".to_string()].into(),
+                ..rule(1, RoleType::NamedActivity)
+            })
+            .unwrap();
+        guild_rules
+            .add_rule(Rule {
+                activities: ["quake".to_string()].into(),
+                activity_types: [ActivityKind::Competing].into(),
+                ..rule(2, RoleType::NamedActivity)
+            })
+            .unwrap();
+        guild_rules
+            .add_rule(Rule {
+                ..rule(3, RoleType::Else)
+            })
+            .unwrap();
+
+        let matched = |user_activities: &[Activity]| {
+            role_ids(&guild_rules.matching_rules(
+                &online(user_activities.to_vec()),
+                &MemberInfo::default(),
+                Utc::now(),
+            ))
+        };
+        assert_eq!(matched(&activities(&["Quake Champions"])), [1].into());
+        assert_eq!(
+            matched(&[activity_of_kind("Quake Champions", ActivityType::Competing)]),
+            [2].into()
+        );
+        assert_eq!(matched(&activities(&["Minecraft"])), [3].into());
+        assert!(matched(&[activity_of_kind("Spotify", ActivityType::Listening)]).is_empty());
+    }
+
+    #[test]
+    fn test_rich_presence() {
+        let rule = compiled(Rule {
+            activities: ["quake".to_string()].into(),
+            rich_presence: RichPresenceMatcher {
+                details: ["ranked".to_string()].into(),
+                min_party_size: Some(2),
+                ..Default::default()
+            },
+            ..rule(0, RoleType::NamedActivity)
+        });
+
+        let mut ranked = activity("Quake Champions");
+        ranked.details = Some("In Ranked Match".to_string());
+        ranked.party = Some(ActivityParty {
+            id: None,
+            size: Some([2, 2]),
+        });
+        assert!(rule.matches_activity(&ranked, false));
+
+        let mut solo = ranked.clone();
+        solo.party = Some(ActivityParty {
+            id: None,
+            size: Some([1, 2]),
+        });
+        assert!(!rule.matches_activity(&solo, false));
+
+        let mut casual = ranked.clone();
+        casual.details = Some("In Menus".to_string());
+        assert!(!rule.matches_activity(&casual, false));
+
+        // Rich presence is folded like activity names
+        let mut full_width = ranked.clone();
+        full_width.details = Some("Ｒａｎｋｅｄ Match".to_string());
+        assert!(rule.matches_activity(&full_width, false));
+        assert!(!rule.matches_activity(&full_width, true));
+
+        assert!(!rule.matches_activity(&activity("Quake Champions"), false));
+    }
+
+    #[test]
+    fn test_streaming_rule() {
+        let mut stream = activity_of_kind("Twitch", ActivityType::Streaming);
+        stream.details = Some("Ranked grind".to_string());
+        stream.state = Some("Quake Champions".to_string());
+
+        let any_stream = compiled(Rule {
+            ..rule(0, RoleType::Streaming)
+        });
+        assert!(any_stream.matches_activity(&stream, false));
+        assert!(!any_stream.matches_activity(&activity("Quake Champions"), false));
+
+        let quake_stream = compiled(Rule {
+            activities: ["quake".to_string()].into(),
+            ..rule(0, RoleType::Streaming)
+        });
+        assert!(quake_stream.matches_activity(&stream, false));
+
+        stream.state = Some("Tekken 8".to_string());
+        assert!(!quake_stream.matches_activity(&stream, false));
+    }
+
+    #[test]
+    fn test_streaming_keeps_default_rule() {
+        let mut guild_rules = GuildRules::new();
+        guild_rules
+            .add_rule(Rule {
+                activities: ["quake".to_string()].into(),
+                ..rule(1, RoleType::NamedActivity)
+            })
+            .unwrap();
+        guild_rules.add_rule(rule(2, RoleType::Else)).unwrap();
+        guild_rules.add_rule(rule(3, RoleType::Streaming)).unwrap();
+
+        let matched = |user_presence: &UserPresence| {
+            role_ids(&guild_rules.matching_rules(user_presence, &MemberInfo::default(), Utc::now()))
+        };
+
+        let stream = activity_of_kind("Twitch", ActivityType::Streaming);
+        assert_eq!(
+            matched(&online(vec![activity("Tekken 8"), stream.clone()])),
+            [2, 3].into()
+        );
+        assert_eq!(
+            matched(&online(vec![activity("Quake Champions"), stream])),
+            [1, 3].into()
+        );
+    }
+
+    #[test]
+    fn test_listening_rule() {
+        let mut song = activity_of_kind("Spotify", ActivityType::Listening);
+        song.details = Some("Master of Puppets".to_string());
+        song.state = Some("Metallica".to_string());
+
+        let metal = compiled(Rule {
+            activities: ["metallica".to_string(), "slayer".to_string()].into(),
+            ..rule(0, RoleType::Listening)
+        });
+        assert!(metal.matches_activity(&song, false));
+        assert!(!metal.matches_activity(&activity("Metallica"), false));
+
+        let puppets_artist = compiled(Rule {
+            activities: ["puppets".to_string()].into(),
+            listening_field: ListeningField::Artist,
+            ..rule(0, RoleType::Listening)
+        });
+        assert!(!puppets_artist.matches_activity(&song, false));
+    }
+
+    #[test]
+    fn test_custom_status_rule() {
+        let rule = compiled(Rule {
+            activities: ["lfg".to_string(), "🎮".to_string()].into(),
+            match_mode: MatchMode::WholeWord,
+            ..rule(0, RoleType::CustomStatus)
+        });
+
+        let mut status = activity_of_kind("Custom Status", ActivityType::Custom);
+        status.state = Some("LFG quake".to_string());
+        assert!(rule.matches_activity(&status, false));
+
+        status.state = Some("do not disturb".to_string());
+        assert!(!rule.matches_activity(&status, false));
+
+        status.emoji = Some(ActivityEmoji {
+            animated: None,
+            name: "🎮".to_string(),
+            id: None,
+        });
+        assert!(rule.matches_activity(&status, false));
+
+        assert!(!rule.matches_activity(&activity("LFG quake"), false));
+    }
+
+    #[test]
+    fn test_idle_rule() {
+        let mut guild_rules = GuildRules::new();
+        let idle_rule = Rule {
+            ..rule(1, RoleType::Idle)
+        };
+        guild_rules.add_rule(idle_rule.clone()).unwrap();
+        assert!(
+            guild_rules
+                .add_rule(Rule {
+                    ..rule(2, RoleType::Idle)
+                })
+                .is_err()
+        );
+
+        assert_eq!(
+            guild_rules.matching_idle_rule(&online(vec![])),
+            Some(&idle_rule)
+        );
+        let listening = activity_of_kind("Spotify", ActivityType::Listening);
+        assert_eq!(
+            guild_rules.matching_idle_rule(&online(vec![listening])),
+            Some(&idle_rule)
+        );
+        assert_eq!(
+            guild_rules.matching_idle_rule(&online(activities(&["Quake"]))),
+            None
+        );
+        assert_eq!(
+            guild_rules.matching_idle_rule(&UserPresence::offline()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_status_rules() {
+        let mut guild_rules = GuildRules::new();
+        guild_rules
+            .add_rule(Rule {
+                statuses: [StatusKind::Online, StatusKind::Idle].into(),
+                ..rule(1, RoleType::Status)
+            })
+            .unwrap();
+        guild_rules
+            .add_rule(Rule {
+                activities: ["quake".to_string()].into(),
+                statuses: [StatusKind::Online, StatusKind::Idle].into(),
+                ..rule(2, RoleType::NamedActivity)
+            })
+            .unwrap();
+        assert!(guild_rules.add_rule(rule(3, RoleType::Status)).is_err());
+
+        let matched = |activities: Vec<Activity>, status: Status| {
+            role_ids(&guild_rules.matching_rules(
+                &UserPresence {
+                    activities,
+                    status,
+                    platforms: [ClientPlatform::Desktop].into(),
+                },
+                &MemberInfo::default(),
+                Utc::now(),
+            ))
+        };
+        assert_eq!(matched(vec![], Status::Online), [1].into());
+        assert_eq!(matched(activities(&["Quake"]), Status::Idle), [1, 2].into());
+        assert!(matched(activities(&["Quake"]), Status::DoNotDisturb).is_empty());
+        assert!(matched(vec![], Status::Offline).is_empty());
+    }
+
+    #[test]
+    fn test_platform_rules() {
+        let mut guild_rules = GuildRules::new();
+        guild_rules
+            .add_rule(Rule {
+                platforms: [ClientPlatform::Mobile].into(),
+                ..rule(1, RoleType::Platform)
+            })
+            .unwrap();
+        guild_rules
+            .add_rule(Rule {
+                activities: ["quake".to_string()].into(),
+                platforms: [ClientPlatform::Desktop].into(),
+                ..rule(2, RoleType::NamedActivity)
+            })
+            .unwrap();
+        assert!(guild_rules.add_rule(rule(3, RoleType::Platform)).is_err());
+
+        let matched = |activities: Vec<Activity>, platforms: &[ClientPlatform]| {
+            role_ids(&guild_rules.matching_rules(
+                &UserPresence {
+                    activities,
+                    status: Status::Online,
+                    platforms: platforms.iter().cloned().collect(),
+                },
+                &MemberInfo::default(),
+                Utc::now(),
+            ))
+        };
+        assert_eq!(matched(vec![], &[ClientPlatform::Mobile]), [1].into());
+        assert_eq!(
+            matched(activities(&["Quake"]), &[ClientPlatform::Desktop]),
+            [2].into()
+        );
+        assert_eq!(
+            matched(
+                activities(&["Quake"]),
+                &[ClientPlatform::Desktop, ClientPlatform::Mobile]
+            ),
+            [1, 2].into()
+        );
+        assert!(matched(activities(&["Quake"]), &[ClientPlatform::Web]).is_empty());
+    }
+
+    #[test]
+    fn test_min_session() {
+        let mut guild_rules = GuildRules::new();
+        guild_rules
+            .add_rule(Rule {
+                activities: ["quake".to_string()].into(),
+                min_session_secs: 300,
+                ..rule(1, RoleType::NamedActivity)
+            })
+            .unwrap();
+
+        let playing_since = |minutes_ago: u64| -> UserPresence {
+            let mut quake = activity("Quake");
+            quake.timestamps = Some(ActivityTimestamps {
+                start: Some(now_millis() - minutes_ago * 60 * 1000),
+                end: None,
+            });
+            online(vec![quake])
+        };
+
+        let just_started = playing_since(1);
+        assert!(
+            guild_rules
+                .matching_rules(&just_started, &MemberInfo::default(), Utc::now())
+                .is_empty()
+        );
+        let pending = guild_rules
+            .pending_session(&just_started, Utc::now())
+            .unwrap();
+        assert!(pending > Duration::from_secs(230) && pending <= Duration::from_secs(240));
+
+        let long_session = playing_since(10);
+        assert_eq!(
+            guild_rules
+                .matching_rules(&long_session, &MemberInfo::default(), Utc::now())
+                .len(),
+            1
+        );
+        assert_eq!(guild_rules.pending_session(&long_session, Utc::now()), None);
+
+        // A huge minimum session never comes due rather than overflowing
+        let forever = Rule {
+            min_session_secs: u64::MAX,
+            ..rule(2, RoleType::NamedActivity)
+        };
+        let quake = &playing_since(1).activities[0];
+        assert!(!forever.remaining_session(quake, now_millis()).is_zero());
+
+        // No start time, nothing to wait on
+        assert_eq!(
+            guild_rules
+                .matching_rules(
+                    &online(activities(&["Quake"])),
+                    &MemberInfo::default(),
+                    Utc::now()
+                )
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_eligibility() {
+        let mut guild_rules = GuildRules::new();
+        guild_rules
+            .add_rule(Rule {
+                activities: ["quake".to_string()].into(),
+                eligibility: Eligibility {
+                    required_roles: [10].into(),
+                    ..Default::default()
+                },
+                ..rule(1, RoleType::NamedActivity)
+            })
+            .unwrap();
+        guild_rules
+            .add_rule(Rule {
+                ..rule(2, RoleType::Else)
+            })
+            .unwrap();
+        guild_rules.settings.eligibility = Eligibility {
+            excluded_roles: [20].into(),
+            skip_bots: true,
+            min_member_days: 7,
+            ..Default::default()
+        };
+
+        let day = 24 * 60 * 60 * 1000;
+        let matched = |member: MemberInfo| {
+            role_ids(
+                &guild_rules
+                    .eligible_rules(&member, Utc::now())
+                    .matching_rules(
+                        &online(activities(&["Quake"])),
+                        &MemberInfo::default(),
+                        Utc::now(),
+                    ),
+            )
+        };
+        let veteran = MemberInfo {
+            user_id: 100,
+            roles: [10].into(),
+            is_bot: false,
+            joined_at: Some(now_millis() - 30 * day),
+            in_voice: false,
+        };
+
+        assert_eq!(matched(veteran.clone()), [1].into());
+        assert_eq!(
+            matched(MemberInfo {
+                roles: BTreeSet::new(),
+                ..veteran.clone()
+            }),
+            [2].into()
+        );
+        assert!(
+            matched(MemberInfo {
+                roles: [10, 20].into(),
+                ..veteran.clone()
+            })
+            .is_empty()
+        );
+        assert!(
+            matched(MemberInfo {
+                is_bot: true,
+                ..veteran.clone()
+            })
+            .is_empty()
+        );
+        assert!(
+            matched(MemberInfo {
+                joined_at: Some(now_millis() - day),
+                ..veteran.clone()
+            })
+            .is_empty()
+        );
+
+        // An absurd minimum membership is never met rather than overflowing
+        let forever = Eligibility {
+            min_member_days: u64::MAX,
+            ..Default::default()
+        };
+        assert!(!forever.allows(&veteran, now_millis()));
+    }
+
+    #[test]
+    fn test_consent() {
+        let mut guild_rules = GuildRules::new();
+        guild_rules
+            .add_rule(Rule {
+                activities: ["quake".to_string()].into(),
+                ..rule(1, RoleType::NamedActivity)
+            })
+            .unwrap();
+        let member = |user_id: u64| MemberInfo {
+            user_id,
+            ..Default::default()
+        };
+        let is_tracked = |guild_rules: &GuildRules, user_id: u64| -> bool {
+            !guild_rules
+                .eligible_rules(&member(user_id), Utc::now())
+                .matching_rules(
+                    &online(activities(&["Quake"])),
+                    &MemberInfo::default(),
+                    Utc::now(),
+                )
+                .is_empty()
+        };
+
+        guild_rules.consent.opted_out = [1].into();
+        assert!(!is_tracked(&guild_rules, 1));
+        assert!(is_tracked(&guild_rules, 2));
+
+        guild_rules.settings.require_opt_in = true;
+        guild_rules.consent.opted_in = [1, 3].into();
+        assert!(!is_tracked(&guild_rules, 1));
+        assert!(!is_tracked(&guild_rules, 2));
+        assert!(is_tracked(&guild_rules, 3));
+
+        // Consent is saved on its own, never with the rules
+        let rules = BTreeMap::from([(0, guild_rules.clone())]);
+        let mut reloaded =
+            load_rules_from_buffer(&rules_to_csv_bytes(&rules).unwrap()[..]).unwrap();
+        assert_eq!(reloaded[&0].consent, MemberConsent::default());
+        load_consent_from_buffer(&mut reloaded, &consent_to_csv_bytes(&rules).unwrap()[..])
+            .unwrap();
+        assert_eq!(reloaded[&0].consent, guild_rules.consent);
 
-    // Collect all rules from all guilds
-    let mut all_csv_rows: Vec<CsvRow> = rules
-        .values()
-        .flat_map(|guild_rules| Into::<Vec<CsvRow>>::into(guild_rules.clone()))
-        .collect();
+        let mut from_storage = BTreeMap::new();
+        keep_consent(&reloaded, &mut from_storage);
+        assert_eq!(from_storage[&0].consent, guild_rules.consent);
+    }
 
-    // Sort by guild_id then by role_name for consistent output
-    all_csv_rows.sort_by(|a, b| match a.guild_id.cmp(&b.guild_id) {
-        std::cmp::Ordering::Equal => a.role_id.cmp(&b.role_id),
-        o => o,
-    });
+    #[test]
+    fn test_schedule() {
+        let friday_night = ScheduleWindow::from_str("Fri 20:00-02:00").unwrap();
+        assert_eq!(friday_night.to_string(), "fri 20:00-02:00");
+        assert!(ScheduleWindow::from_str("fri 25:00-02:00").is_none());
+        assert!(ScheduleWindow::from_str("someday 20:00-02:00").is_none());
+        assert!(ScheduleWindow::from_str("monkey 20:00-02:00").is_none());
+        assert!(ScheduleWindow::from_str("fr 20:00-02:00").is_none());
+        assert_eq!(
+            ScheduleWindow::from_str("Friday 20:00-02:00"),
+            Some(friday_night.clone())
+        );
 
-    // Write all rows
-    for row in all_csv_rows {
-        wtr.serialize(row)?;
+        let mut guild_rules = GuildRules::new();
+        guild_rules
+            .add_rule(Rule {
+                activities: ["quake".to_string()].into(),
+                schedule: [friday_night].into(),
+                ..rule(1, RoleType::NamedActivity)
+            })
+            .unwrap();
+        guild_rules.settings.time_zone = "Asia/Jerusalem".to_string();
+
+        let scheduled_at = |utc: &str| -> bool {
+            let now = DateTime::parse_from_rfc3339(utc).unwrap().to_utc();
+            !guild_rules.active_rules(now).all_rules().is_empty()
+        };
+        // 2025-01-03 is a friday, Jerusalem is UTC+2 in the winter
+        assert!(!scheduled_at("2025-01-03T17:59:00Z"));
+        assert!(scheduled_at("2025-01-03T18:00:00Z"));
+        assert!(scheduled_at("2025-01-03T23:59:00Z"));
+        assert!(!scheduled_at("2025-01-04T00:00:00Z"));
     }
 
-    wtr.flush()?;
-    Ok(wtr.into_inner()?)
-}
+    #[test]
+    fn test_rule_expiry() {
+        let expires_at = GuildSettings::default()
+            .parse_time("2025-06-01 18:00")
+            .unwrap();
+        assert_eq!(expires_at.to_rfc3339(), "2025-06-01T18:00:00+00:00");
 
-pub fn save_rules_to_file(rules: &BTreeMap<u64, GuildRules>, file_path: String) -> Result<()> {
-    let csv_bytes = rules_to_csv_bytes(rules)?;
-    std::fs::write(file_path, csv_bytes)?;
-    Ok(())
-}
+        let mut guild_rules = GuildRules::new();
+        guild_rules
+            .add_rule(Rule {
+                activities: ["quake".to_string()].into(),
+                expires_at: Some(expires_at),
+                ..rule(1, RoleType::NamedActivity)
+            })
+            .unwrap();
 
-pub fn save_db_to_file(rules: &BTreeMap<u64, GuildRules>) -> Result<()> {
-    save_rules_to_file(rules, "db/db.csv".to_string())
-}
+        let before = expires_at - chrono::Duration::minutes(1);
+        assert_eq!(guild_rules.active_rules(before).all_rules().len(), 1);
+        assert!(guild_rules.expired_rules(before).is_empty());
 
-pub async fn save_current_db_to_file(rules: Arc<RwLock<BTreeMap<u64, GuildRules>>>) -> Result<()> {
-    let rules = rules.read().await;
-    save_db_to_file(&rules)
-}
+        assert!(guild_rules.active_rules(expires_at).all_rules().is_empty());
+        let archived = guild_rules.expired_rules(expires_at);
+        assert_eq!(archived.len(), 1);
+        // Still in effect until its role is taken away and it's archived
+        assert!(guild_rules.get_rule(1).is_some());
+        guild_rules.archive_rule(1).unwrap();
+        assert!(guild_rules.get_rule(1).is_none());
+        assert!(guild_rules.expired_rules(expires_at).is_empty());
+        assert!(guild_rules.archive_rule(1).is_err());
 
-pub async fn save_db_to_github(
-    rules: &BTreeMap<u64, GuildRules>,
-    github_config: &GithubConfig,
-) -> Result<()> {
-    let csv_bytes = rules_to_csv_bytes(rules)?;
-    let bytes = Bytes::from(csv_bytes);
+        let reloaded = load_rules_from_buffer(
+            rules_to_csv_bytes(&[(0, guild_rules)].into())
+                .unwrap()
+                .as_slice(),
+        )
+        .unwrap();
+        let reloaded = reloaded.get(&0).unwrap();
+        assert!(reloaded.all_rules().is_empty());
+        assert_eq!(reloaded.archived_rules.get(&1), archived.first());
+    }
 
-    upload_bytes_to_github(
-        &bytes,
-        &github_config.owner,
-        &github_config.repo,
-        &github_config.path,
-        &github_config.branch,
-    )
-    .await
-}
+    #[tokio::test]
+    async fn test_catalogs() {
+        let mut guild_rules = GuildRules::new();
+        guild_rules
+            .add_rule(Rule {
+                guild_id: 2,
+                ..rule(1, RoleType::NamedActivity)
+            })
+            .unwrap();
+        let rules = Arc::new(RwLock::new(BTreeMap::from([(2, guild_rules)])));
 
-pub async fn load_db(github_config: Option<&GithubConfig>) -> BTreeMap<u64, GuildRules> {
-    if let Ok(db) = load_db_from_file() {
-        db
-    } else if let Some(github_config) = github_config
-        && let Ok(db) = load_rules_from_github(github_config).await
-    {
-        save_db_to_file(&db).unwrap();
-        db
-    } else {
-        BTreeMap::new()
+        update_catalog(
+            &rules,
+            1,
+            "quake-likes",
+            ["quake".to_string()].into(),
+            BTreeSet::new(),
+        )
+        .await
+        .unwrap();
+        assert!(
+            update_catalog(&rules, 2, "quake-likes", BTreeSet::new(), BTreeSet::new())
+                .await
+                .is_err()
+        );
+
+        let attach = |catalog: &str| RuleUpdate {
+            catalogs: Some([catalog.to_string()].into()),
+            ..Default::default()
+        };
+        assert!(
+            update_role_rule(&rules, 2, 1, attach("doom-likes"))
+                .await
+                .is_err()
+        );
+        update_role_rule(&rules, 2, 1, attach("quake-likes"))
+            .await
+            .unwrap();
+
+        let matched = |rules: &BTreeMap<u64, GuildRules>, name: &str| {
+            role_ids(&rules.get(&2).unwrap().matching_rules(
+                &online(activities(&[name])),
+                &MemberInfo::default(),
+                Utc::now(),
+            ))
+        };
+        assert_eq!(matched(&*rules.read().await, "Quake Live"), [1].into());
+        assert!(matched(&*rules.read().await, "Xonotic").is_empty());
+
+        update_catalog(
+            &rules,
+            1,
+            "quake-likes",
+            ["xonotic".to_string()].into(),
+            BTreeSet::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(matched(&*rules.read().await, "Xonotic"), [1].into());
+        assert!(delete_catalog(&rules, 1, "quake-likes").await.is_err());
+
+        assert_eq!(
+            get_catalog(&rules, "quake-likes").await.unwrap(),
+            ["quake".to_string(), "xonotic".to_string()].into()
+        );
+        let (guild_users, other_users) = catalog_users(&rules, 1, "quake-likes").await;
+        assert!(guild_users.is_empty());
+        assert_eq!(other_users, 1);
+        let (guild_users, other_users) = catalog_users(&rules, 2, "quake-likes").await;
+        assert_eq!(guild_users.len(), 1);
+        assert_eq!(other_users, 0);
+
+        // Only removals don't create a catalog
+        assert!(
+            update_catalog(
+                &rules,
+                1,
+                "doom-likes",
+                BTreeSet::new(),
+                ["doom".to_string()].into()
+            )
+            .await
+            .is_err()
+        );
+        assert!(get_catalog(&rules, "doom-likes").await.is_err());
+
+        // Regex rules only match their own patterns
+        let regex_rule = Rule {
+            activities: ["^quake".to_string()].into(),
+            catalogs: ["quake-likes".to_string()].into(),
+            ..rule(3, RoleType::Regex)
+        };
+        assert!(GuildRules::new().add_rule(regex_rule).is_err());
+
+        let reloaded = reload(&*rules.read().await);
+        assert_eq!(reloaded, *rules.read().await);
+        assert_eq!(matched(&reloaded, "Xonotic"), [1].into());
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::{config_handler, github_handler};
+    #[test]
+    fn test_match_modes() {
+        let threshold = DEFAULT_FUZZY_THRESHOLD;
+        assert!(MatchMode::Substring.matches("marvel vs. capcom", "vs.", threshold));
+        assert!(MatchMode::WholeWord.matches("marvel vs. capcom", "vs.", threshold));
+        assert!(!MatchMode::WholeWord.matches("canvas", "vs", threshold));
+        assert!(MatchMode::WholeWord.matches("street fighter 6", "street fighter", threshold));
+        assert!(!MatchMode::WholeWord.matches("streetfighter 6", "street fighter", threshold));
+        assert!(MatchMode::Exact.matches("quake", "quake", threshold));
+        assert!(!MatchMode::Exact.matches("quake champions", "quake", threshold));
+        assert!(MatchMode::Prefix.matches("quake champions", "quake", threshold));
+        assert!(!MatchMode::Prefix.matches("enemy territory: quake wars", "quake", threshold));
+        assert!(MatchMode::Fuzzy.matches("tekken 8 (beta)", "tekken 8", threshold));
+        assert!(MatchMode::Fuzzy.matches("tekekn 8", "tekken 8", threshold));
+        assert!(MatchMode::Fuzzy.matches("street fighter 6 eu", "street fighter", threshold));
+        assert!(!MatchMode::Fuzzy.matches("doom", "quake", threshold));
+        assert!(!MatchMode::Fuzzy.matches("tekekn 8", "tekken 8", 90));
+    }
 
-    #[allow(unused_imports)]
-    use super::*;
+    #[test]
+    fn test_normalized_names() {
+        assert_eq!(normalize_name("Quake Champions™"), "quake champions");
+        assert_eq!(normalize_name("DNF  Duel®"), "dnf duel");
+        assert_eq!(normalize_name("ＴＥＫＫＥＮ　８"), "tekken 8");
+        assert_eq!(normalize_name("Pokémon Café"), "pokemon cafe");
 
-    #[tokio::test]
-    async fn test_save_db_to_file() {
-        let _ = save_rules_to_file(&load_db(None).await, "db_test.csv".to_string());
+        let mut guild_rules = GuildRules::new();
+        guild_rules
+            .add_rule(Rule {
+                activities: ["pokemon cafe".to_string()].into(),
+                match_mode: MatchMode::Exact,
+                ..rule(1, RoleType::NamedActivity)
+            })
+            .unwrap();
+        let matches = |guild_rules: &GuildRules| {
+            !guild_rules
+                .matching_rules(
+                    &online(activities(&["Pokémon Café™"])),
+                    &MemberInfo::default(),
+                    Utc::now(),
+                )
+                .is_empty()
+        };
+        assert!(matches(&guild_rules));
+
+        guild_rules.settings.raw_activity_names = true;
+        assert!(!matches(&guild_rules));
+
+        let reloaded = reload(&[(0, guild_rules)].into());
+        assert!(!matches(reloaded.get(&0).unwrap()));
     }
 
     #[tokio::test]
-    async fn test_save_db_equals() {
-        assert_eq!(
-            load_rules_from_file("db.csv".to_string()).unwrap(),
-            load_rules_from_github(&GithubConfig::new().unwrap())
+    async fn test_aliases() {
+        let mut guild_rules = GuildRules::new();
+        guild_rules
+            .add_rule(Rule {
+                activities: ["Quake Champions".to_string()].into(),
+                match_mode: MatchMode::Exact,
+                ..rule(1, RoleType::NamedActivity)
+            })
+            .unwrap();
+        let rules = Arc::new(RwLock::new(BTreeMap::from([(0, guild_rules)])));
+
+        let aliases = ["QC".to_string(), "quakechampions.exe".to_string()].into();
+        add_aliases(&rules, 0, "Quake Champions", aliases).await;
+        add_aliases(&rules, 0, "Quake Live", ["ql".to_string()].into()).await;
+
+        let matches = |rules: &BTreeMap<u64, GuildRules>, name: &str| {
+            let guild_rules = rules.get(&0).unwrap();
+            let user_presence = online(activities(&[name]));
+            !guild_rules
+                .matching_rules(
+                    &guild_rules.with_aliases(&user_presence),
+                    &MemberInfo::default(),
+                    Utc::now(),
+                )
+                .is_empty()
+        };
+        assert!(matches(&*rules.read().await, "qc"));
+        assert!(matches(&*rules.read().await, "QuakeChampions.exe"));
+        assert!(!matches(&*rules.read().await, "QL"));
+
+        add_aliases(&rules, 0, "Quake Champions", ["QL".to_string()].into()).await;
+        assert!(matches(&*rules.read().await, "ql"));
+        assert!(
+            !rules
+                .read()
                 .await
+                .get(&0)
                 .unwrap()
+                .aliases()
+                .contains_key("Quake Live")
+        );
+
+        assert!(
+            remove_aliases(&rules, 0, &["qw".to_string()].into())
+                .await
+                .is_err()
+        );
+        remove_aliases(&rules, 0, &["qc".to_string()].into())
+            .await
+            .unwrap();
+        assert!(!matches(&*rules.read().await, "QC"));
+
+        let reloaded = reload(&*rules.read().await);
+        assert_eq!(reloaded, *rules.read().await);
+        assert!(matches(&reloaded, "quakechampions.exe"));
+
+        // Aliases are looked up folded the way the setting says
+        add_aliases(
+            &rules,
+            0,
+            "Quake Champions",
+            ["Ｑｕａｋｅ".to_string()].into(),
         )
+        .await;
+        assert!(matches(&*rules.read().await, "quake"));
+        set_raw_activity_names(&rules, 0, true).await;
+        assert!(!matches(&*rules.read().await, "quake"));
+        assert!(matches(&*rules.read().await, "ｑｕａｋｅ"));
+
+        let presence = online(activities(&["Tekken 8"]));
+        assert!(matches!(
+            rules.read().await.get(&0).unwrap().with_aliases(&presence),
+            Cow::Borrowed(_)
+        ));
     }
 
     #[test]
-    fn test_rule_named_activity() {
-        let row = CsvRow {
-            guild_id: "0".to_string(),
-            guild_name: "guild_name".to_string(),
-            role_id: "0".to_string(),
-            role_name: "role1".to_string(),
-            role_type: "named-activity".to_string(),
-            activity_names: "Game1;Game2".to_string(),
-            comments: "".to_string(),
+    fn test_conditions() {
+        let condition = Condition::parse(
+            "activity:\"Tekken 8\" and voice AND not (status:dnd OR status:idle) or role:<@&10>",
+        )
+        .unwrap();
+
+        let mut guild_rules = GuildRules::new();
+        guild_rules
+            .add_rule(Rule {
+                condition: Some(condition.clone()),
+                exclusions: ["demo".to_string()].into(),
+                ..rule(1, RoleType::NamedActivity)
+            })
+            .unwrap();
+        guild_rules
+            .add_rule(Rule {
+                condition: Some(Condition::parse("role:10 OR joined:30d").unwrap()),
+                ..rule(2, RoleType::NamedActivity)
+            })
+            .unwrap();
+        guild_rules.add_rule(rule(3, RoleType::Else)).unwrap();
+
+        let day = 24 * 60 * 60 * 1000;
+        let member = MemberInfo {
+            joined_at: Some(now_millis() - day),
+            ..Default::default()
+        };
+        let matched = |activities: Vec<Activity>, status: Status, member: &MemberInfo| {
+            role_ids(&guild_rules.matching_rules(
+                &UserPresence {
+                    activities,
+                    status,
+                    platforms: [ClientPlatform::Desktop].into(),
+                },
+                member,
+                Utc::now(),
+            ))
+        };
+
+        let in_voice = MemberInfo {
+            in_voice: true,
+            ..member.clone()
         };
-        let rule: Rule = row.into();
         assert_eq!(
-            rule,
-            Rule {
-                guild_id: 0,
-                guild_name: "guild_name".to_string(),
-                role_id: 0,
-                role_name: "role1".to_string(),
-                role_type: RoleType::NamedActivity,
-                activities: ["Game1", "Game2"].iter().map(|s| s.to_string()).collect(),
-                comments: "".to_string(),
-            }
-        )
+            matched(activities(&["TEKKEN 8"]), Status::Online, &in_voice),
+            [1].into()
+        );
+        assert_eq!(
+            matched(activities(&["TEKKEN 8"]), Status::Online, &member),
+            [3].into()
+        );
+        assert_eq!(
+            matched(activities(&["TEKKEN 8"]), Status::DoNotDisturb, &in_voice),
+            [3].into()
+        );
+        // The rule's exclusions hold for the activities of its condition too
+        assert_eq!(
+            matched(activities(&["Tekken 8 Demo"]), Status::Online, &in_voice),
+            [3].into()
+        );
+
+        let verified = MemberInfo {
+            roles: [10].into(),
+            ..member.clone()
+        };
+        assert_eq!(matched(vec![], Status::Online, &verified), [1, 2].into());
+        // Matched for the role, not the game, so the default stays
+        assert_eq!(
+            matched(activities(&["TEKKEN 8"]), Status::Online, &verified),
+            [1, 2, 3].into()
+        );
+        let verified_in_voice = MemberInfo {
+            in_voice: true,
+            ..verified.clone()
+        };
+        assert_eq!(
+            matched(
+                activities(&["TEKKEN 8"]),
+                Status::Online,
+                &verified_in_voice
+            ),
+            [1, 2].into()
+        );
+        let veteran = MemberInfo {
+            joined_at: Some(now_millis() - 31 * day),
+            ..member
+        };
+        assert_eq!(matched(vec![], Status::Online, &veteran), [2].into());
+        assert_eq!(
+            matched(activities(&["Quake"]), Status::Online, &veteran),
+            [2, 3].into()
+        );
+
+        let reloaded = reload(&[(0, guild_rules.clone())].into());
+        assert_eq!(reloaded.get(&0), Some(&guild_rules));
+
+        // Rules the condition couldn't apply to are rejected
+        for role_type in [
+            RoleType::Else,
+            RoleType::Idle,
+            RoleType::Status,
+            RoleType::Platform,
+        ] {
+            let conditional = Rule {
+                condition: Some(condition.clone()),
+                statuses: [StatusKind::Online].into(),
+                platforms: [ClientPlatform::Desktop].into(),
+                ..rule(3, role_type)
+            };
+            assert!(GuildRules::new().add_rule(conditional).is_err());
+        }
+        let timed = Rule {
+            condition: Some(condition),
+            min_session_secs: 60,
+            ..rule(3, RoleType::NamedActivity)
+        };
+        assert!(GuildRules::new().add_rule(timed).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fuzzy_threshold() {
+        let mut rule = Rule {
+            activities: ["Tekken 8".to_string()].into(),
+            match_mode: MatchMode::Fuzzy,
+            ..rule(1, RoleType::NamedActivity)
+        };
+        rule.compile().unwrap();
+        assert!(rule.matches_name("Tekekn 8", false));
+        assert!(rule.matches_name("TEKKEN 8 (Beta)", false));
+        assert!(!rule.matches_name("Tekken Tag Tournament", false));
+
+        rule.fuzzy_threshold = Some(90);
+        assert!(!rule.matches_name("Tekekn 8", false));
+        assert!(rule.matches_name("TEKKEN 8 (Beta)", false));
+
+        let field: EmbedField = rule.clone().into();
+        assert!(field.value.starts_with("fuzzy (90%) match: Tekken 8"));
+
+        let row: CsvRow = rule.clone().into();
+        assert_eq!(row.fuzzy_threshold, "90");
+        assert_eq!(Rule::try_from(row).unwrap(), rule);
+
+        let mut guild_rules = GuildRules::new();
+        guild_rules.add_rule(rule.clone()).unwrap();
+        let rules = Arc::new(RwLock::new(BTreeMap::from([(rule.guild_id, guild_rules)])));
+        let reset = RuleUpdate {
+            fuzzy_threshold: Some(None),
+            ..Default::default()
+        };
+        let updated = update_role_rule(&rules, rule.guild_id, rule.role_id, reset)
+            .await
+            .unwrap();
+        assert_eq!(updated.fuzzy_threshold, None);
+        assert!(updated.matches_name("Tekekn 8", false));
     }
 
     #[test]
-    fn test_rule_else() {
+    fn test_invalid_regex_rejected() {
         let row = CsvRow {
             guild_id: "0".to_string(),
             guild_name: "guild_name".to_string(),
             role_id: "0".to_string(),
             role_name: "role1".to_string(),
-            role_type: "else".to_string(),
-            activity_names: "Game1;Game2".to_string(),
+            role_type: "regex".to_string(),
+            activity_names: "street (fighter".to_string(),
             comments: "".to_string(),
+            ..Default::default()
         };
-        let rule: Rule = row.into();
-        assert_eq!(
-            rule,
-            Rule {
-                guild_id: 0,
-                guild_name: "guild_name".to_string(),
-                role_id: 0,
-                role_name: "role1".to_string(),
-                role_type: RoleType::Else,
-                activities: ["Game1", "Game2"].iter().map(|s| s.to_string()).collect(),
-                comments: "".to_string(),
-            }
-        )
+        let mut rule: Rule = row.try_into().unwrap();
+        assert!(rule.compile().is_err());
+        assert!(GuildRules::new().add_rule(rule).is_err());
     }
 
     #[test]
-    fn test_guild_rules_activity() {
-        let named_rule = Rule {
-            guild_id: 0,
-            guild_name: "guild_name".to_string(),
-            role_id: 0,
-            role_name: "role1".to_string(),
-            role_type: RoleType::NamedActivity,
-            activities: ["Game1", "Game2"].iter().map(|s| s.to_string()).collect(),
-            comments: "".to_string(),
-        };
-        let else_rule = Rule {
-            guild_id: 0,
-            guild_name: "guild_name".to_string(),
-            role_id: 0,
-            role_name: "role1".to_string(),
-            role_type: RoleType::Else,
-            activities: ["Game1", "Game2"].iter().map(|s| s.to_string()).collect(),
-            comments: "".to_string(),
-        };
-
-        let mut guild_rules = GuildRules::new();
-        guild_rules
-            .activities_rules
-            .insert(named_rule.role_id, named_rule);
-        guild_rules.default_rule = Some(else_rule);
+    fn test_invalid_rows_rejected() {
+        let header = "guild_id,guild_name,role_id,role_name,type,activity_names,priority,required_roles,comments\n";
+        let load = |row: &str| load_rules_from_buffer(format!("{}{}\n", header, row).as_bytes());
 
-        let user_activities = ["AGame1"].iter().map(|s| s.to_string()).collect();
+        assert!(load("1,guild,2,role,named-activity,quake,,,").is_ok());
+        assert!(load("1,guild,2,role,named-activity,quake,high,,").is_err());
+        assert!(load("1,guild,2,role,unknown,quake,,,").is_err());
+        assert!(load("1,guild,role,role,named-activity,quake,,,").is_err());
+        assert!(load("1,guild,0,,guild-settings,,,admins,").is_err());
 
-        assert_eq!(
-            guild_rules.matching_rules(user_activities),
-            guild_rules.activities_rules.values().cloned().collect()
-        );
-
-        let user_activities = ["asd"].iter().map(|s| s.to_string()).collect();
-
-        assert_eq!(
-            guild_rules.matching_rules(user_activities),
-            guild_rules.default_rule.iter().cloned().collect()
-        );
+        let settings_header = "guild_id,required_roles,excluded_roles,skip_bots,min_member_days,require_opt_in,time_zone,raw_activity_names\n";
+        let load_settings = |row: &str| {
+            load_settings_from_buffer(
+                &mut BTreeMap::new(),
+                format!("{}{}\n", settings_header, row).as_bytes(),
+            )
+        };
+        assert!(load_settings("1,2;3,,true,30,false,Europe/Paris,false").is_ok());
+        assert!(load_settings("1,admins,,true,30,false,Europe/Paris,false").is_err());
+        assert!(load_settings("1,,,maybe,30,false,Europe/Paris,false").is_err());
     }
 
     #[tokio::test(flavor = "multi_thread", worker_threads = 1)]